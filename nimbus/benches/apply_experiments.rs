@@ -0,0 +1,106 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Benchmarks of `apply_pending_experiments()` against a database which already
+// holds the same set of experiments, which is what happens on most app launches.
+//
+// To compare two versions of the library, run `cargo bench -- --save-baseline before`
+// on the first one and `cargo bench -- --baseline before` on the second.
+//
+// Note that with the default "safe mode" backend, every commit rewrites the whole
+// database file, however few records were written in the transaction. Applying
+// unchanged experiments writes no records, and doesn't rebuild the cache or its
+// snapshot, but it still commits to remove the pending experiments, so it saves
+// serializing and storing the unchanged records but not that file write. The
+// "unchanged" case is expected to gain much less than with the LMDB backend
+// (`--no-default-features --features uniffi-bindings`).
+// No numbers have been recorded for either backend yet.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use nimbus::{AppContext, NimbusClient};
+use serde_json::json;
+use tempdir::TempDir;
+
+fn experiments_json(count: usize, name: &str) -> String {
+    let experiments: Vec<_> = (0..count)
+        .map(|i| {
+            let slug = format!("experiment-{}", i);
+            json!({
+                "schemaVersion": "1.0.0",
+                "slug": slug,
+                "branches": [
+                    {"slug": "control", "ratio": 1},
+                    {"slug": "treatment", "ratio": 1}
+                ],
+                "featureIds": [format!("feature-{}", i)],
+                "probeSets": [],
+                "bucketConfig": {
+                    "count": 5_000,
+                    "start": 0,
+                    "total": 10_000,
+                    "namespace": slug,
+                    "randomizationUnit": "nimbus_id"
+                },
+                "userFacingName": name,
+                "userFacingDescription": "A benchmark experiment",
+                "isEnrollmentPaused": false,
+                "proposedEnrollment": 7,
+            })
+        })
+        .collect();
+    json!({ "data": experiments }).to_string()
+}
+
+fn apply_experiments(c: &mut Criterion) {
+    let mut group = c.benchmark_group("apply_pending_experiments");
+    for count in &[10, 100, 500] {
+        let tmp_dir = TempDir::new("bench_apply_experiments").unwrap();
+        let client = NimbusClient::new(
+            AppContext::default(),
+            tmp_dir.path(),
+            None,
+            Default::default(),
+            None,
+        )
+        .unwrap();
+        client.initialize().unwrap();
+        let unchanged = experiments_json(*count, "Unchanged");
+        client.set_experiments_locally(unchanged.clone()).unwrap();
+        client.apply_pending_experiments().unwrap();
+
+        group.bench_with_input(
+            BenchmarkId::new("unchanged", count),
+            &unchanged,
+            |b, json| {
+                b.iter_batched(
+                    || client.set_experiments_locally(json.clone()).unwrap(),
+                    |_| client.apply_pending_experiments().unwrap(),
+                    BatchSize::SmallInput,
+                )
+            },
+        );
+
+        // Every experiment changes, and changes back, on alternate iterations.
+        let changed = [experiments_json(*count, "Changed"), unchanged];
+        let mut next = 0;
+        group.bench_with_input(
+            BenchmarkId::new("all_changed", count),
+            &changed,
+            |b, jsons| {
+                b.iter_batched(
+                    || {
+                        next = (next + 1) % jsons.len();
+                        client.set_experiments_locally(jsons[next].clone()).unwrap()
+                    },
+                    |_| client.apply_pending_experiments().unwrap(),
+                    BatchSize::SmallInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, apply_experiments);
+criterion_main!(benches);
@@ -200,14 +200,17 @@ fn main() -> Result<()> {
         server_url: server_url.to_string(),
         bucket_name: bucket_name.to_string(),
         collection_name: collection_name.to_string(),
+        override_collections: vec![],
+        root_hash: None,
     };
 
     let aru = AvailableRandomizationUnits::with_client_id(&client_id);
 
     // Here we initialize our main `NimbusClient` struct
-    let nimbus_client = NimbusClient::new(context.clone(), "", Some(config), aru)?;
+    let nimbus_client = NimbusClient::new(context.clone(), "", Some(config), aru, None)?;
 
     // Explicitly update experiments at least once for init purposes
+    nimbus_client.initialize()?;
     nimbus_client.fetch_experiments()?;
     nimbus_client.apply_pending_experiments()?;
 
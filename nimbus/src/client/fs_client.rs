@@ -8,11 +8,13 @@
 
 use crate::error::Result;
 use crate::Experiment;
+use crate::ExperimentsMetadata;
 use crate::SettingsClient;
 use std::ffi::OsStr;
 use std::fs::File;
 use std::io::BufReader;
 use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
 
 pub struct FileSystemClient {
     path: PathBuf,
@@ -24,25 +26,46 @@ impl FileSystemClient {
             path: path.as_ref().into(),
         })
     }
+
+    // The experiment files in our directory, skipping directories and non .json
+    // files (eg, READMEs).
+    fn experiment_files(&self) -> Result<Vec<PathBuf>> {
+        let json_ext = Some(OsStr::new("json"));
+        Ok(self
+            .path
+            .read_dir()?
+            .filter_map(Result::ok)
+            .map(|c| c.path())
+            .filter(|f| f.is_file() && f.extension() == json_ext)
+            .collect())
+    }
 }
 
 impl SettingsClient for FileSystemClient {
-    fn get_experiments_metadata(&self) -> Result<String> {
-        unimplemented!();
+    // The equivalent of the collection's timestamp is the time (in milliseconds,
+    // like Remote Settings) at which an experiment file was last modified.
+    fn get_experiments_metadata(&self) -> Result<ExperimentsMetadata> {
+        let files = self.experiment_files()?;
+        let mut last_modified = None;
+        for file in &files {
+            let modified = file.metadata()?.modified()?;
+            let millis = modified
+                .duration_since(UNIX_EPOCH)
+                .map(|since_epoch| since_epoch.as_millis() as u64)
+                .unwrap_or(0);
+            last_modified = last_modified.max(Some(millis));
+        }
+        Ok(ExperimentsMetadata {
+            last_modified,
+            record_count: Some(files.len() as u64),
+            signature: None,
+        })
     }
 
     fn fetch_experiments(&self) -> Result<Vec<Experiment>> {
         log::info!("reading experiments in {}", self.path.display());
         let mut res = Vec::new();
-        // Skip directories and non .json files (eg, READMEs)
-        let json_ext = Some(OsStr::new("json"));
-        let filenames = self
-            .path
-            .read_dir()?
-            .filter_map(Result::ok)
-            .map(|c| c.path())
-            .filter(|f| f.is_file() && f.extension() == json_ext);
-        for child_path in filenames {
+        for child_path in self.experiment_files()? {
             let file = File::open(child_path.clone())?;
             let reader = BufReader::new(file);
             match serde_json::from_reader::<_, Experiment>(reader) {
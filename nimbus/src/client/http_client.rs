@@ -12,70 +12,174 @@
 //!   Issue: https://github.com/mozilla/application-services/issues/3475
 //!
 //! But the simple subset implemented here meets our needs for now.
+//!
+//! The records are only trusted once the content signature of the collection
+//! has been verified, see the `signatures` module.
 
-use std::time::{Duration, Instant};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 
+use super::signatures;
 use crate::config::RemoteSettingsConfig;
 use crate::error::{NimbusError, Result};
-use crate::{Experiment, SettingsClient, SCHEMA_VERSION};
-use std::cell::Cell;
+use crate::{
+    CollectionSignature, CollectionState, Experiment, ExperimentsMetadata, SettingsClient,
+    SCHEMA_VERSION,
+};
+use ring::rand::{SecureRandom, SystemRandom};
 use url::Url;
-use viaduct::{status_codes, Request, Response};
+use viaduct::{status_codes, Method, Request, Response};
 
 const HEADER_BACKOFF: &str = "Backoff";
 const HEADER_RETRY_AFTER: &str = "Retry-After";
+const HEADER_ETAG: &str = "ETag";
+const HEADER_IF_NONE_MATCH: &str = "If-None-Match";
+const HEADER_TOTAL_RECORDS: &str = "Total-Records";
+const HEADER_NEXT_PAGE: &str = "Next-Page";
+
+// The most pages and records we fetch for the collection. Experiments are few,
+// so a collection larger than that is more likely a problem with the server
+// than something we should spend the resources of the device on.
+const MAX_PAGES: usize = 20;
+const MAX_RECORDS: usize = 5000;
+
+// How many times we try a request which fails because of the network or the
+// server, and the bounds of the delay between those attempts, which doubles
+// after each of them.
+const MAX_ATTEMPTS: u32 = 3;
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(10);
 
 pub struct Client {
     base_url: Url,
     collection_name: String,
     bucket_name: String,
-    remote_state: Cell<RemoteState>,
+    remote_state: Mutex<RemoteState>,
+    // The SHA-256 hash of the root of the certificate chains we trust to sign
+    // the collection, which depends on the server unless it's configured.
+    root_hash: String,
+    // The delay before retrying a failed request for the first time.
+    retry_base_delay: Duration,
 }
 
 #[derive(Clone, Copy, Debug)]
 enum RemoteState {
     Ok,
-    Backoff {
-        observed_at: Instant,
-        duration: Duration,
-    },
+    // We use the wall-clock time, so that the deadline can be persisted and
+    // still be honored after a restart.
+    Backoff { until: SystemTime },
 }
 
 impl Client {
     #[allow(unused)]
     pub fn new(config: RemoteSettingsConfig) -> Result<Self> {
         let base_url = Url::parse(&config.server_url)?;
+        let root_hash = match config.root_hash {
+            Some(root_hash) if signatures::is_valid_root_hash(&root_hash) => root_hash,
+            Some(root_hash) => {
+                return Err(NimbusError::InvalidConfig(format!(
+                    "invalid root hash: {}",
+                    root_hash
+                )))
+            }
+            None => signatures::root_hash_for(&base_url).to_string(),
+        };
         Ok(Self {
+            root_hash,
             base_url,
             bucket_name: config.bucket_name,
             collection_name: config.collection_name,
-            remote_state: Cell::new(RemoteState::Ok),
+            remote_state: Mutex::new(RemoteState::Ok),
+            retry_base_delay: RETRY_BASE_DELAY,
         })
     }
 
+    fn collection_url(&self) -> Result<Url> {
+        let path = format!(
+            "buckets/{}/collections/{}",
+            &self.bucket_name, &self.collection_name
+        );
+        Ok(self.base_url.join(&path)?)
+    }
+
+    fn records_url(&self) -> Result<Url> {
+        let path = format!(
+            "buckets/{}/collections/{}/records",
+            &self.bucket_name, &self.collection_name
+        );
+        Ok(self.base_url.join(&path)?)
+    }
+
     fn make_request(&self, request: Request) -> Result<Response> {
         self.ensure_no_backoff()?;
-        let resp = request.send()?;
-        self.handle_backoff_hint(&resp)?;
-        if resp.is_success() || resp.status == status_codes::NOT_MODIFIED {
-            Ok(resp)
-        } else {
-            Err(NimbusError::ResponseError(resp.text().to_string()))
+        self.send_with_retries(request, |resp| self.handle_backoff_hint(resp))
+    }
+
+    // Send `request`, and try again a few times if it fails because of the
+    // network or the server, unless the server asked us to back off.
+    // `on_response` is called with every response we get.
+    fn send_with_retries(
+        &self,
+        request: Request,
+        on_response: impl Fn(&Response) -> Result<()>,
+    ) -> Result<Response> {
+        let mut attempt = 1;
+        loop {
+            let error = match request.clone().send() {
+                Ok(resp) => {
+                    on_response(&resp)?;
+                    match check_response(resp) {
+                        Ok(resp) => return Ok(resp),
+                        Err(e) => e,
+                    }
+                }
+                Err(e) => NimbusError::RequestError(e),
+            };
+            let is_transient = matches!(
+                error,
+                NimbusError::RequestError(_) | NimbusError::ServerResponseError(..)
+            );
+            let backing_off = matches!(
+                *self.remote_state.lock().unwrap(),
+                RemoteState::Backoff { .. }
+            );
+            if !is_transient || backing_off || attempt >= MAX_ATTEMPTS {
+                return Err(error);
+            }
+            let delay = self.retry_delay(attempt);
+            log::warn!("{}, retrying in {:?}", error, delay);
+            std::thread::sleep(delay);
+            attempt += 1;
         }
     }
 
+    // The delay before the next attempt after `attempt` failed. We use "equal
+    // jitter": a random delay between half and all of the exponential delay, so
+    // that clients which failed at the same time don't all retry at the same time.
+    fn retry_delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .retry_base_delay
+            .checked_mul(1 << (attempt - 1).min(16))
+            .map_or(RETRY_MAX_DELAY, |delay| delay.min(RETRY_MAX_DELAY));
+        let mut random = [0u8; 4];
+        // Without randomness, we just don't add any jitter.
+        let jitter = match SystemRandom::new().fill(&mut random) {
+            Ok(()) => f64::from(u32::from_le_bytes(random)) / f64::from(u32::MAX),
+            Err(_) => 1.0,
+        };
+        delay / 2 + (delay / 2).mul_f64(jitter)
+    }
+
     fn ensure_no_backoff(&self) -> Result<()> {
-        if let RemoteState::Backoff {
-            observed_at,
-            duration,
-        } = self.remote_state.get()
-        {
-            let elapsed_time = observed_at.elapsed();
-            if elapsed_time >= duration {
-                self.remote_state.replace(RemoteState::Ok);
-            } else {
-                let remaining = duration - elapsed_time;
-                return Err(NimbusError::BackoffError(remaining.as_secs()));
+        let mut remote_state = self.remote_state.lock().unwrap();
+        if let RemoteState::Backoff { until } = *remote_state {
+            match until.duration_since(SystemTime::now()) {
+                Ok(remaining) if remaining > Duration::from_secs(0) => {
+                    return Err(NimbusError::BackoffError(remaining.as_secs()));
+                }
+                _ => {
+                    *remote_state = RemoteState::Ok;
+                }
             }
         }
         Ok(())
@@ -96,45 +200,265 @@ impl Client {
         let max_backoff = backoff.max(retry_after);
 
         if max_backoff > 0 {
-            self.remote_state.replace(RemoteState::Backoff {
-                observed_at: Instant::now(),
-                duration: Duration::from_secs(max_backoff),
-            });
+            *self.remote_state.lock().unwrap() = RemoteState::Backoff {
+                until: SystemTime::now() + Duration::from_secs(max_backoff),
+            };
         }
         Ok(())
     }
 }
 
 impl SettingsClient for Client {
-    fn get_experiments_metadata(&self) -> Result<String> {
-        unimplemented!();
+    // The timestamp and number of records come from a `HEAD` request on the
+    // records, since the timestamp of the collection object itself is the time its
+    // metadata last changed, and the signature comes from the collection object.
+    fn get_experiments_metadata(&self) -> Result<ExperimentsMetadata> {
+        let resp = self.make_request(Request::new(Method::Head, self.records_url()?))?;
+        let last_modified = get_etag_timestamp(&resp);
+        let record_count = resp
+            .headers
+            .get_as::<u64, _>(HEADER_TOTAL_RECORDS)
+            .transpose()
+            .unwrap_or_default(); // Ignore number parsing errors.
+
+        let resp = self.make_request(Request::get(self.collection_url()?))?;
+        let value: serde_json::Value = serde_json::from_str(&resp.text())?;
+        let signature = match value.pointer("/data/signature") {
+            Some(signature) => Some(serde_json::from_value::<CollectionSignature>(
+                signature.clone(),
+            )?),
+            None => None,
+        };
+        Ok(ExperimentsMetadata {
+            last_modified,
+            record_count,
+            signature,
+        })
     }
 
     fn fetch_experiments(&self) -> Result<Vec<Experiment>> {
-        let path = format!(
-            "buckets/{}/collections/{}/records",
-            &self.bucket_name, &self.collection_name
-        );
-        let url = self.base_url.join(&path)?;
-        let req = Request::get(url);
-        let resp = self.make_request(req)?;
-        parse_experiments(&resp.text())
+        // Without a timestamp, this always fetches the whole collection.
+        let mut state = CollectionState::default();
+        Ok(self.sync_experiments(&mut state)?.unwrap_or_default())
+    }
+
+    // We first check the metadata of the collection, and if we know its timestamp
+    // and it didn't change, we're done. Otherwise we ask the server for the
+    // records which changed (or were deleted) since. We don't send `_expected`,
+    // since we don't know which timestamp to expect without polling the changes
+    // endpoint.
+    //
+    // Either way, `state` is only updated once the signature of the resulting
+    // collection has been verified. If that fails after applying only the
+    // changes, we try again with the whole collection, in case our copy of the
+    // records was what didn't match.
+    fn sync_experiments(&self, state: &mut CollectionState) -> Result<Option<Vec<Experiment>>> {
+        let metadata = self.get_experiments_metadata()?;
+        let mut new_state = state.clone();
+        if new_state.last_modified.is_some() && metadata.last_modified == new_state.last_modified {
+            let record_count = new_state.records.len() as u64;
+            if metadata
+                .record_count
+                .map_or(true, |count| count == record_count)
+            {
+                return Ok(None);
+            }
+            // We must have missed some changes, so start again from scratch.
+            log::warn!(
+                "Expected {:?} records but have {}, fetching all of them again",
+                metadata.record_count,
+                record_count
+            );
+            new_state = CollectionState::default();
+        }
+        let fetched_changes = new_state.last_modified.is_some();
+        if !self.fetch_records(&mut new_state)? {
+            return Ok(None);
+        }
+        if let Err(e) = self.verify_collection(&new_state, &metadata) {
+            if !fetched_changes {
+                return Err(e);
+            }
+            log::warn!(
+                "{} after fetching the changes, fetching all records again",
+                e
+            );
+            new_state = CollectionState::default();
+            self.fetch_records(&mut new_state)?;
+            self.verify_collection(&new_state, &metadata)?;
+        }
+        *state = new_state;
+        Ok(Some(parse_experiment_records(state.records.values())))
+    }
+
+    fn backoff_deadline(&self) -> Option<SystemTime> {
+        match *self.remote_state.lock().unwrap() {
+            RemoteState::Ok => None,
+            RemoteState::Backoff { until } => Some(until),
+        }
+    }
+
+    fn set_backoff_deadline(&self, deadline: Option<SystemTime>) {
+        *self.remote_state.lock().unwrap() = match deadline {
+            Some(until) => RemoteState::Backoff { until },
+            None => RemoteState::Ok,
+        };
     }
 }
 
+impl Client {
+    // Apply the records which changed since `state` was last synced to it, or
+    // replace its records with the whole collection if it never was. Returns
+    // whether anything changed.
+    fn fetch_records(&self, state: &mut CollectionState) -> Result<bool> {
+        let mut url = self.records_url()?;
+        let req = match state.last_modified {
+            Some(last_modified) => {
+                url.query_pairs_mut()
+                    .append_pair("_since", &last_modified.to_string());
+                Request::get(url).header(HEADER_IF_NONE_MATCH, format!("\"{}\"", last_modified))?
+            }
+            None => Request::get(url),
+        };
+        let mut resp = self.make_request(req)?;
+        if resp.status == status_codes::NOT_MODIFIED {
+            return Ok(false);
+        }
+        // All the pages share the timestamp of the collection.
+        let etag_timestamp = get_etag_timestamp(&resp);
+        let mut records = Vec::new();
+        let mut pages = 1;
+        loop {
+            let value: serde_json::Value = serde_json::from_str(&resp.text())?;
+            records.extend(get_records(&value)?.iter().cloned());
+            if records.len() > MAX_RECORDS {
+                return Err(NimbusError::CollectionTooLarge(format!(
+                    "more than {} records",
+                    MAX_RECORDS
+                )));
+            }
+            let next_page = match resp.headers.get(HEADER_NEXT_PAGE) {
+                Some(next_page) => self.next_page_url(next_page)?,
+                None => break,
+            };
+            pages += 1;
+            if pages > MAX_PAGES {
+                return Err(NimbusError::CollectionTooLarge(format!(
+                    "more than {} pages",
+                    MAX_PAGES
+                )));
+            }
+            resp = self.make_request(Request::get(next_page))?;
+        }
+        if state.last_modified.is_none() {
+            // We fetched the whole collection, which replaces whatever we had.
+            state.records.clear();
+        }
+        for record in &records {
+            state.apply_change(record);
+        }
+        let records_last_modified = records
+            .iter()
+            .filter_map(|record| record.get("last_modified").and_then(|ts| ts.as_u64()))
+            .max();
+        state.last_modified = etag_timestamp
+            .or(records_last_modified)
+            .or(state.last_modified);
+        Ok(true)
+    }
+
+    // The URL of the next page of records, which we only follow to the server
+    // we were configured with.
+    fn next_page_url(&self, next_page: &str) -> Result<Url> {
+        let url = Url::parse(next_page)?;
+        if url.origin() != self.base_url.origin() {
+            return Err(NimbusError::ResponseError(format!(
+                "Unexpected next page of records: {}",
+                next_page
+            )));
+        }
+        Ok(url)
+    }
+
+    // Check that the records of `state` are what the collection was signed with.
+    fn verify_collection(
+        &self,
+        state: &CollectionState,
+        metadata: &ExperimentsMetadata,
+    ) -> Result<()> {
+        let signature = metadata
+            .signature
+            .as_ref()
+            .ok_or_else(|| NimbusError::SignatureError("The collection isn't signed".into()))?;
+        let last_modified = state
+            .last_modified
+            .or(metadata.last_modified)
+            .ok_or_else(|| NimbusError::SignatureError("The collection has no timestamp".into()))?;
+        // The certificate chain is usually served by a CDN rather than by the
+        // server, so this request isn't subject to its backoff hints.
+        let resp = self.send_with_retries(Request::get(Url::parse(&signature.x5u)?), |_| Ok(()))?;
+        let payload = signatures::collection_payload(state.records.values(), last_modified);
+        signatures::verify_signature(
+            payload.as_bytes(),
+            &signature.signature,
+            &resp.body,
+            &self.root_hash,
+        )
+    }
+}
+
+// Turn unsuccessful responses into the error for their kind of status. Only
+// errors of the server are worth retrying.
+fn check_response(resp: Response) -> Result<Response> {
+    if resp.is_success() || resp.status == status_codes::NOT_MODIFIED {
+        Ok(resp)
+    } else if resp.is_client_error() {
+        Err(NimbusError::ClientResponseError(
+            resp.status,
+            resp.text().to_string(),
+        ))
+    } else if resp.is_server_error() {
+        Err(NimbusError::ServerResponseError(
+            resp.status,
+            resp.text().to_string(),
+        ))
+    } else {
+        Err(NimbusError::ResponseError(resp.text().to_string()))
+    }
+}
+
+// The ETag of a collection is its timestamp, in quotes.
+fn get_etag_timestamp(resp: &Response) -> Option<u64> {
+    resp.headers
+        .get(HEADER_ETAG)?
+        .trim_start_matches("W/")
+        .trim_matches('"')
+        .parse()
+        .ok()
+}
+
+fn get_records(value: &serde_json::Value) -> Result<&Vec<serde_json::Value>> {
+    value
+        .get("data")
+        .and_then(|data| data.as_array())
+        .ok_or(NimbusError::InvalidExperimentFormat)
+}
+
 pub fn parse_experiments(payload: &str) -> Result<Vec<Experiment>> {
     // We first encode the response into a `serde_json::Value`
     // to allow us to deserialize each experiment individually,
     // omitting any malformed experiments
     let value: serde_json::Value = serde_json::from_str(payload)?;
-    let data = value
-        .get("data")
-        .ok_or(NimbusError::InvalidExperimentFormat)?;
+    Ok(parse_experiment_records(get_records(&value)?))
+}
+
+/// Deserialize the experiments in `records`, skipping the ones which are malformed
+/// or have an unsupported schema version.
+fn parse_experiment_records<'a>(
+    records: impl IntoIterator<Item = &'a serde_json::Value>,
+) -> Vec<Experiment> {
     let mut res = Vec::new();
-    for exp in data
-        .as_array()
-        .ok_or(NimbusError::InvalidExperimentFormat)?
-    {
+    for exp in records {
         // Validate the schema major version matches the supported version
         let exp_schema_version = match exp.get("schemaVersion") {
             Some(ver) => {
@@ -170,20 +494,91 @@ pub fn parse_experiments(payload: &str) -> Result<Vec<Experiment>> {
             }
         }
     }
-    Ok(res)
+    res
 }
 
 #[cfg(test)]
 mod tests {
+    use super::signatures::test_utils::{sign, TEST_CHAIN, TEST_ROOT_HASH};
+    use super::signatures::MOZILLA_ROOT_HASH;
     use super::*;
     use crate::{Branch, BucketConfig, FeatureConfig, RandomizationUnit};
-    use mockito::mock;
+    use mockito::{mock, Mock};
+    use serde_json::json;
+
+    // A client which trusts the test certificate chain.
+    fn test_client() -> Client {
+        let config = RemoteSettingsConfig {
+            server_url: mockito::server_url(),
+            bucket_name: "main".to_string(),
+            collection_name: "messaging-experiments".to_string(),
+            override_collections: vec![],
+            root_hash: Some(TEST_ROOT_HASH.to_string()),
+        };
+        let mut client = Client::new(config).unwrap();
+        // Don't make the tests which retry requests slow.
+        client.retry_base_delay = Duration::from_millis(1);
+        client
+    }
+
+    // Mock the requests `get_experiments_metadata()` makes, for a collection
+    // holding `records` and signed with the test certificate chain, and the one
+    // for the chain itself.
+    fn mock_collection(records: &[serde_json::Value], last_modified: u64) -> Vec<Mock> {
+        let signature = sign(&signatures::collection_payload(records, last_modified));
+        mock_metadata(
+            last_modified,
+            records.len() as u64,
+            Some(json!({
+                "signature": signature,
+                "x5u": format!("{}/chain.pem", mockito::server_url())
+            })),
+        )
+    }
+
+    fn mock_metadata(
+        last_modified: u64,
+        record_count: u64,
+        signature: Option<serde_json::Value>,
+    ) -> Vec<Mock> {
+        let head = mock(
+            "HEAD",
+            "/buckets/main/collections/messaging-experiments/records",
+        )
+        .with_status(200)
+        .with_header("ETag", &format!("\"{}\"", last_modified))
+        .with_header("Total-Records", &record_count.to_string())
+        .create();
+        let mut data = json!({
+            "id": "messaging-experiments",
+            "last_modified": last_modified + 1,
+        });
+        if let Some(signature) = signature {
+            data["signature"] = signature;
+        }
+        let collection = mock("GET", "/buckets/main/collections/messaging-experiments")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(json!({ "data": data }).to_string())
+            .create();
+        let chain = mock("GET", "/chain.pem")
+            .with_status(200)
+            .with_body(TEST_CHAIN)
+            .create();
+        vec![head, collection, chain]
+    }
+
+    fn response_records() -> Vec<serde_json::Value> {
+        let value: serde_json::Value = serde_json::from_str(&response_body()).unwrap();
+        get_records(&value).unwrap().clone()
+    }
 
     fn response_body() -> String {
         format!(
             r#"
         {{ "data": [
             {{
+                "id": "mobile-a-a-example",
                 "schemaVersion": "{current_version}.0.0",
                 "slug": "mobile-a-a-example",
                 "appName": "reference-browser",
@@ -224,6 +619,7 @@ mod tests {
                 ]
             }},
             {{
+                "id": "mobile-a-a-example-newer",
                 "schemaVersion": "{newer_version}.0.0",
                 "slug": "mobile-a-a-example",
                 "appName": "reference-browser",
@@ -256,6 +652,7 @@ mod tests {
                 ]
             }},
             {{
+                "id": "schema-version-missing",
                 "slug": "schema-version-missing",
                 "appName": "reference-browser",
                 "userFacingName": "Schema Version Missing",
@@ -271,6 +668,7 @@ mod tests {
     #[test]
     fn test_fetch_experiments_from_schema() {
         viaduct_reqwest::use_reqwest_backend();
+        let _collection = mock_collection(&response_records(), 10);
         // There are two experiments defined here, one has a "newer" schema version
         // in order to test filtering of unsupported schema versions.
         let m = mock(
@@ -281,12 +679,7 @@ mod tests {
         .with_status(200)
         .with_header("content-type", "application/json")
         .create();
-        let config = RemoteSettingsConfig {
-            server_url: mockito::server_url(),
-            bucket_name: "main".to_string(),
-            collection_name: "messaging-experiments".to_string(),
-        };
-        let http_client = Client::new(config).unwrap();
+        let http_client = test_client();
         let resp = http_client.fetch_experiments().unwrap();
 
         m.expect(1).assert();
@@ -343,6 +736,7 @@ mod tests {
     #[test]
     fn test_backoff() {
         viaduct_reqwest::use_reqwest_backend();
+        let _collection = mock_collection(&response_records(), 10);
         let m = mock(
             "GET",
             "/buckets/main/collections/messaging-experiments/records",
@@ -352,12 +746,7 @@ mod tests {
         .with_header("content-type", "application/json")
         .with_header("Backoff", "60")
         .create();
-        let config = RemoteSettingsConfig {
-            server_url: mockito::server_url(),
-            bucket_name: "main".to_string(),
-            collection_name: "messaging-experiments".to_string(),
-        };
-        let http_client = Client::new(config).unwrap();
+        let http_client = test_client();
         assert!(http_client.fetch_experiments().is_ok());
         let second_request = http_client.fetch_experiments();
         assert!(matches!(second_request, Err(NimbusError::BackoffError(_))));
@@ -367,6 +756,7 @@ mod tests {
     #[test]
     fn test_500_retry_after() {
         viaduct_reqwest::use_reqwest_backend();
+        let _collection = mock_collection(&[], 10);
         let m = mock(
             "GET",
             "/buckets/main/collections/messaging-experiments/records",
@@ -375,21 +765,116 @@ mod tests {
         .with_status(500)
         .with_header("Retry-After", "60")
         .create();
-        let config = RemoteSettingsConfig {
+        let http_client = test_client();
+        // We don't retry when the server asked us to back off.
+        assert!(matches!(
+            http_client.fetch_experiments(),
+            Err(NimbusError::ServerResponseError(500, _))
+        ));
+        let second_request = http_client.fetch_experiments();
+        assert!(matches!(second_request, Err(NimbusError::BackoffError(_))));
+        m.expect(1).assert();
+    }
+
+    #[test]
+    fn test_retries() {
+        viaduct_reqwest::use_reqwest_backend();
+        let path = "/buckets/main/collections/messaging-experiments/records";
+        let http_client = test_client();
+
+        // Errors of the server are retried a few times...
+        {
+            let m = mock("HEAD", path).with_status(503).create();
+            assert!(matches!(
+                http_client.get_experiments_metadata(),
+                Err(NimbusError::ServerResponseError(503, _))
+            ));
+            m.expect(MAX_ATTEMPTS as usize).assert();
+        }
+
+        // ...and as many as needed if the request eventually succeeds. Mocks
+        // which match are used in the order they were created, until they got
+        // the hits they expect.
+        {
+            let failure = mock("HEAD", path).with_status(502).expect(1).create();
+            let _collection = mock_collection(&response_records(), 10);
+            assert!(http_client.get_experiments_metadata().is_ok());
+            failure.assert();
+        }
+
+        // Errors of the client aren't.
+        {
+            let m = mock("HEAD", path).with_status(404).create();
+            assert!(matches!(
+                http_client.fetch_experiments(),
+                Err(NimbusError::ClientResponseError(404, _))
+            ));
+            m.expect(1).assert();
+        }
+    }
+
+    #[test]
+    fn test_configured_root_hash() {
+        let config = |root_hash: Option<&str>| RemoteSettingsConfig {
             server_url: mockito::server_url(),
             bucket_name: "main".to_string(),
             collection_name: "messaging-experiments".to_string(),
+            override_collections: vec![],
+            root_hash: root_hash.map(str::to_string),
         };
-        let http_client = Client::new(config).unwrap();
-        assert!(http_client.fetch_experiments().is_err());
-        let second_request = http_client.fetch_experiments();
-        assert!(matches!(second_request, Err(NimbusError::BackoffError(_))));
-        m.expect(1).assert();
+        // Servers are trusted to be signed like Mozilla's by default...
+        assert_eq!(
+            Client::new(config(None)).unwrap().root_hash,
+            MOZILLA_ROOT_HASH
+        );
+        // ...unless they're configured with another root.
+        assert_eq!(
+            Client::new(config(Some(TEST_ROOT_HASH))).unwrap().root_hash,
+            TEST_ROOT_HASH
+        );
+        assert!(matches!(
+            Client::new(config(Some("not a hash"))),
+            Err(NimbusError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_transport_error() {
+        viaduct_reqwest::use_reqwest_backend();
+        // Nothing listens on the discard port, so connecting fails.
+        let config = RemoteSettingsConfig {
+            server_url: "http://localhost:9".to_string(),
+            bucket_name: "main".to_string(),
+            collection_name: "messaging-experiments".to_string(),
+            override_collections: vec![],
+            root_hash: None,
+        };
+        let mut http_client = Client::new(config).unwrap();
+        http_client.retry_base_delay = Duration::from_millis(1);
+        assert!(matches!(
+            http_client.fetch_experiments(),
+            Err(NimbusError::RequestError(_))
+        ));
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let http_client = test_client();
+        for attempt in 1..10 {
+            let max = (http_client.retry_base_delay * (1 << (attempt - 1))).min(RETRY_MAX_DELAY);
+            let delay = http_client.retry_delay(attempt);
+            assert!(delay >= max / 2 && delay <= max, "{:?}", delay);
+        }
+        let mut http_client = test_client();
+        http_client.retry_base_delay = RETRY_BASE_DELAY;
+        assert!(http_client.retry_delay(1) <= RETRY_BASE_DELAY);
+        assert!(http_client.retry_delay(100) >= RETRY_MAX_DELAY / 2);
     }
 
     #[test]
     fn test_backoff_recovery() {
         viaduct_reqwest::use_reqwest_backend();
+        let _collection = mock_collection(&response_records(), 10);
         let m = mock(
             "GET",
             "/buckets/main/collections/messaging-experiments/records",
@@ -398,27 +883,334 @@ mod tests {
         .with_status(200)
         .with_header("content-type", "application/json")
         .create();
-        let config = RemoteSettingsConfig {
-            server_url: mockito::server_url(),
-            bucket_name: "main".to_string(),
-            collection_name: "messaging-experiments".to_string(),
-        };
-        let mut http_client = Client::new(config).unwrap();
+        let mut http_client = test_client();
         // First, sanity check that manipulating the remote state does something.
-        http_client.remote_state.replace(RemoteState::Backoff {
-            observed_at: Instant::now(),
-            duration: Duration::from_secs(30),
+        http_client.remote_state = Mutex::new(RemoteState::Backoff {
+            until: SystemTime::now() + Duration::from_secs(30),
         });
         assert!(matches!(
             http_client.fetch_experiments(),
             Err(NimbusError::BackoffError(_))
         ));
         // Then do the actual test.
-        http_client.remote_state = Cell::new(RemoteState::Backoff {
-            observed_at: Instant::now() - Duration::from_secs(31),
-            duration: Duration::from_secs(30),
+        http_client.remote_state = Mutex::new(RemoteState::Backoff {
+            until: SystemTime::now() - Duration::from_secs(1),
         });
         assert!(http_client.fetch_experiments().is_ok());
         m.expect(1).assert();
     }
+
+    #[test]
+    fn test_backoff_deadline() {
+        viaduct_reqwest::use_reqwest_backend();
+        let m = mock(
+            "HEAD",
+            "/buckets/main/collections/messaging-experiments/records",
+        )
+        .with_status(503)
+        .with_header("Retry-After", "60")
+        .create();
+        let http_client = test_client();
+        assert_eq!(http_client.backoff_deadline(), None);
+        assert!(http_client.fetch_experiments().is_err());
+        let deadline = http_client.backoff_deadline().unwrap();
+        assert!(deadline > SystemTime::now() + Duration::from_secs(50));
+
+        // A new client, eg, after a restart, honors the deadline it's given...
+        let http_client = test_client();
+        http_client.set_backoff_deadline(Some(deadline));
+        assert!(matches!(
+            http_client.fetch_experiments(),
+            Err(NimbusError::BackoffError(_))
+        ));
+        m.expect(1).assert();
+        // ...until it's over.
+        http_client.set_backoff_deadline(Some(SystemTime::now() - Duration::from_secs(1)));
+        assert!(matches!(
+            http_client.fetch_experiments(),
+            Err(NimbusError::ServerResponseError(..))
+        ));
+        assert_eq!(http_client.backoff_deadline(), None);
+    }
+
+    #[test]
+    fn test_get_experiments_metadata() {
+        viaduct_reqwest::use_reqwest_backend();
+        let _collection = mock_collection(&response_records(), 20);
+        let metadata = test_client().get_experiments_metadata().unwrap();
+        assert_eq!(metadata.last_modified, Some(20));
+        assert_eq!(metadata.record_count, Some(3));
+        assert_eq!(
+            metadata.signature.unwrap().x5u,
+            format!("{}/chain.pem", mockito::server_url())
+        );
+    }
+
+    fn record(id: &str, last_modified: u64) -> serde_json::Value {
+        let mut record = response_records().swap_remove(0);
+        record["id"] = json!(id);
+        record["slug"] = json!(id);
+        record["last_modified"] = json!(last_modified);
+        record
+    }
+
+    #[test]
+    fn test_incremental_sync() {
+        use mockito::Matcher;
+
+        viaduct_reqwest::use_reqwest_backend();
+        let path =
+            Matcher::Regex("^/buckets/main/collections/messaging-experiments/records".into());
+        let http_client = test_client();
+        let mut state = CollectionState::default();
+
+        // The first sync fetches the whole collection.
+        {
+            let _collection = mock_collection(&[record("a", 10), record("b", 20)], 20);
+            let m = mock("GET", path.clone())
+                .match_query(Matcher::Missing)
+                .with_body(json!({"data": [record("a", 10), record("b", 20)]}).to_string())
+                .with_status(200)
+                .with_header("ETag", "\"20\"")
+                .create();
+            let experiments = http_client.sync_experiments(&mut state).unwrap().unwrap();
+            m.expect(1).assert();
+            assert_eq!(experiments.len(), 2);
+            assert_eq!(state.last_modified, Some(20));
+        }
+
+        // The next one only fetches what changed since, including deletions.
+        {
+            let _collection = mock_collection(&[record("b", 20), record("c", 30)], 30);
+            let m = mock("GET", path.clone())
+                .match_query(Matcher::UrlEncoded("_since".into(), "20".into()))
+                .match_header("If-None-Match", "\"20\"")
+                .with_body(
+                    json!({"data": [
+                        record("c", 30),
+                        {"id": "a", "deleted": true, "last_modified": 30}
+                    ]})
+                    .to_string(),
+                )
+                .with_status(200)
+                .with_header("ETag", "\"30\"")
+                .create();
+            let experiments = http_client.sync_experiments(&mut state).unwrap().unwrap();
+            m.expect(1).assert();
+            let slugs: Vec<_> = experiments.iter().map(|e| e.slug.as_str()).collect();
+            assert_eq!(slugs, vec!["b", "c"]);
+            assert_eq!(state.last_modified, Some(30));
+        }
+
+        // When the metadata says nothing changed, we don't fetch the records at all.
+        {
+            let _collection = mock_collection(&[record("b", 20), record("c", 30)], 30);
+            let m = mock("GET", path.clone()).expect(0).create();
+            let before = state.clone();
+            assert!(http_client.sync_experiments(&mut state).unwrap().is_none());
+            m.assert();
+            assert_eq!(state, before);
+        }
+
+        // Nor when the server tells us the records didn't change.
+        {
+            let _collection = mock_collection(&[record("b", 20), record("c", 30)], 40);
+            let m = mock("GET", path.clone())
+                .match_query(Matcher::UrlEncoded("_since".into(), "30".into()))
+                .with_status(304)
+                .create();
+            assert!(http_client.sync_experiments(&mut state).unwrap().is_none());
+            m.expect(1).assert();
+            assert_eq!(state.last_modified, Some(30));
+        }
+
+        // But if our records don't add up, we fetch all of them again.
+        {
+            let all = vec![record("b", 20), record("c", 30), record("d", 30)];
+            let _collection = mock_collection(&all, 30);
+            let m = mock("GET", path)
+                .match_query(Matcher::Missing)
+                .with_body(json!({ "data": all }).to_string())
+                .with_status(200)
+                .with_header("ETag", "\"30\"")
+                .create();
+            let experiments = http_client.sync_experiments(&mut state).unwrap().unwrap();
+            m.expect(1).assert();
+            assert_eq!(experiments.len(), 3);
+        }
+    }
+
+    #[test]
+    fn test_signature_verification() {
+        use mockito::Matcher;
+
+        viaduct_reqwest::use_reqwest_backend();
+        let path =
+            Matcher::Regex("^/buckets/main/collections/messaging-experiments/records".into());
+        let mut state = CollectionState::default();
+
+        // Records which don't match the signature are rejected, and not synced.
+        {
+            let _collection = mock_collection(&[record("a", 10)], 10);
+            let _m = mock("GET", path.clone())
+                .with_body(json!({"data": [record("a", 10), record("evil", 10)]}).to_string())
+                .with_status(200)
+                .with_header("ETag", "\"10\"")
+                .create();
+            assert!(matches!(
+                test_client().sync_experiments(&mut state),
+                Err(NimbusError::SignatureError(_))
+            ));
+            assert_eq!(state, CollectionState::default());
+        }
+
+        // So are collections which are signed by a chain we don't trust...
+        {
+            let _collection = mock_collection(&[record("a", 10)], 10);
+            let _m = mock("GET", path.clone())
+                .with_body(json!({"data": [record("a", 10)]}).to_string())
+                .with_status(200)
+                .with_header("ETag", "\"10\"")
+                .create();
+            let mut http_client = test_client();
+            http_client.root_hash = MOZILLA_ROOT_HASH.to_string();
+            assert!(matches!(
+                http_client.sync_experiments(&mut state),
+                Err(NimbusError::SignatureError(_))
+            ));
+        }
+        // ...or not signed at all.
+        {
+            let _metadata = mock_metadata(10, 1, None);
+            let _m = mock("GET", path.clone())
+                .with_body(json!({"data": [record("a", 10)]}).to_string())
+                .with_status(200)
+                .with_header("ETag", "\"10\"")
+                .create();
+            assert!(matches!(
+                test_client().fetch_experiments(),
+                Err(NimbusError::SignatureError(_))
+            ));
+        }
+
+        // When the changes we fetched don't give the signed collection, we fetch
+        // the whole collection, in case it's our copy which was wrong.
+        state.last_modified = Some(10);
+        state.records.insert("a".into(), record("a", 10));
+        state.records.insert("gone".into(), record("gone", 5));
+        {
+            let _collection = mock_collection(&[record("a", 10), record("b", 20)], 20);
+            let changes = mock("GET", path.clone())
+                .match_query(Matcher::UrlEncoded("_since".into(), "10".into()))
+                .with_body(json!({"data": [record("b", 20)]}).to_string())
+                .with_status(200)
+                .with_header("ETag", "\"20\"")
+                .create();
+            let all = mock("GET", path)
+                .match_query(Matcher::Missing)
+                .with_body(json!({"data": [record("a", 10), record("b", 20)]}).to_string())
+                .with_status(200)
+                .with_header("ETag", "\"20\"")
+                .create();
+            let experiments = test_client().sync_experiments(&mut state).unwrap().unwrap();
+            changes.expect(1).assert();
+            all.expect(1).assert();
+            assert_eq!(experiments.len(), 2);
+            assert_eq!(state.records.keys().collect::<Vec<_>>(), vec!["a", "b"]);
+        }
+    }
+
+    #[test]
+    fn test_pagination() {
+        use mockito::Matcher;
+
+        viaduct_reqwest::use_reqwest_backend();
+        let path =
+            Matcher::Regex("^/buckets/main/collections/messaging-experiments/records".into());
+        let next_page = |token: &str| {
+            format!(
+                "{}/buckets/main/collections/messaging-experiments/records?_token={}",
+                mockito::server_url(),
+                token
+            )
+        };
+
+        // The pages are all fetched, and their records put together.
+        {
+            let _collection =
+                mock_collection(&[record("a", 10), record("b", 20), record("c", 20)], 20);
+            let first = mock("GET", path.clone())
+                .match_query(Matcher::Missing)
+                .with_body(json!({"data": [record("a", 10)]}).to_string())
+                .with_status(200)
+                .with_header("ETag", "\"20\"")
+                .with_header("Next-Page", &next_page("two"))
+                .create();
+            let second = mock("GET", path.clone())
+                .match_query(Matcher::UrlEncoded("_token".into(), "two".into()))
+                .with_body(json!({"data": [record("b", 20)]}).to_string())
+                .with_status(200)
+                .with_header("ETag", "\"20\"")
+                .with_header("Next-Page", &next_page("three"))
+                .create();
+            let third = mock("GET", path.clone())
+                .match_query(Matcher::UrlEncoded("_token".into(), "three".into()))
+                .with_body(json!({"data": [record("c", 20)]}).to_string())
+                .with_status(200)
+                .with_header("ETag", "\"20\"")
+                .create();
+            let mut state = CollectionState::default();
+            let experiments = test_client().sync_experiments(&mut state).unwrap().unwrap();
+            first.expect(1).assert();
+            second.expect(1).assert();
+            third.expect(1).assert();
+            assert_eq!(experiments.len(), 3);
+            assert_eq!(state.last_modified, Some(20));
+        }
+
+        // But we give up on collections which never end...
+        {
+            let _collection = mock_collection(&[record("a", 10)], 10);
+            let m = mock("GET", path.clone())
+                .with_body(json!({"data": [record("a", 10)]}).to_string())
+                .with_status(200)
+                .with_header("Next-Page", &next_page("again"))
+                .create();
+            assert!(matches!(
+                test_client().fetch_experiments(),
+                Err(NimbusError::CollectionTooLarge(_))
+            ));
+            m.expect(MAX_PAGES).assert();
+        }
+
+        // ...or have too many records...
+        {
+            let records: Vec<_> = (0..=MAX_RECORDS)
+                .map(|i| json!({"id": i.to_string(), "last_modified": 10}))
+                .collect();
+            let _collection = mock_collection(&records, 10);
+            let _m = mock("GET", path.clone())
+                .with_body(json!({ "data": records }).to_string())
+                .with_status(200)
+                .create();
+            assert!(matches!(
+                test_client().fetch_experiments(),
+                Err(NimbusError::CollectionTooLarge(_))
+            ));
+        }
+
+        // ...and don't follow pages to other servers.
+        {
+            let _collection = mock_collection(&[record("a", 10)], 10);
+            let _m = mock("GET", path)
+                .with_body(json!({"data": [record("a", 10)]}).to_string())
+                .with_status(200)
+                .with_header("Next-Page", "https://example.com/records?_token=two")
+                .create();
+            assert!(matches!(
+                test_client().fetch_experiments(),
+                Err(NimbusError::ResponseError(_))
+            ));
+        }
+    }
 }
@@ -4,20 +4,26 @@
 
 mod fs_client;
 mod http_client;
+mod multi_client;
 mod null_client;
+mod signatures;
 use crate::error::{NimbusError, Result};
 use crate::Experiment;
 use crate::RemoteSettingsConfig;
 use fs_client::FileSystemClient;
 use http_client::Client;
+use multi_client::MultiClient;
 use null_client::NullClient;
+use serde_derive::*;
+use std::collections::BTreeMap;
+use std::time::SystemTime;
 use url::Url;
 
 pub use http_client::parse_experiments;
 
 pub(crate) fn create_client(
     config: Option<RemoteSettingsConfig>,
-) -> Result<Box<dyn SettingsClient + Send>> {
+) -> Result<Box<dyn SettingsClient>> {
     Ok(match config {
         Some(config) => {
             // XXX - double-parsing the URL here if it's not a file:// URL - ideally
@@ -26,14 +32,34 @@ pub(crate) fn create_client(
             if url.scheme() == "file" {
                 // Everything in `config` other than the url/path is ignored for the
                 // file-system - we could insist on a sub-directory, but that doesn't
-                // seem valuable for the use-cases we care about here.
+                // seem valuable for the use-cases we care about here. Override
+                // collections can't be ignored without changing which experiments
+                // we get though, so we reject them.
+                if !config.override_collections.is_empty() {
+                    return Err(NimbusError::InvalidConfig(
+                        "override collections aren't supported for file:// URLs".to_string(),
+                    ));
+                }
                 let path = match url.to_file_path() {
                     Ok(path) => path,
                     _ => return Err(NimbusError::InvalidPath(config.server_url)),
                 };
                 Box::new(FileSystemClient::new(path)?)
-            } else {
+            } else if config.override_collections.is_empty() {
                 Box::new(Client::new(config)?)
+            } else {
+                let mut clients: Vec<Box<dyn SettingsClient>> =
+                    vec![Box::new(Client::new(config.clone())?)];
+                for collection in config.override_collections {
+                    clients.push(Box::new(Client::new(RemoteSettingsConfig {
+                        server_url: config.server_url.clone(),
+                        bucket_name: collection.bucket_name,
+                        collection_name: collection.collection_name,
+                        override_collections: vec![],
+                        root_hash: config.root_hash.clone(),
+                    })?));
+                }
+                Box::new(MultiClient::new(clients))
             }
         }
         // If no server is provided, then we still want Nimbus to work, but serving
@@ -42,8 +68,79 @@ pub(crate) fn create_client(
     })
 }
 
-// The trait used to fetch experiments.
-pub(crate) trait SettingsClient {
-    fn get_experiments_metadata(&self) -> Result<String>;
+// The trait used to fetch experiments. Clients are shared between threads, so
+// that a fetch doesn't need to hold the lock on the current client.
+pub(crate) trait SettingsClient: Send + Sync {
+    fn get_experiments_metadata(&self) -> Result<ExperimentsMetadata>;
     fn fetch_experiments(&self) -> Result<Vec<Experiment>>;
+
+    // Bring `state` up to date with the collection on the server, and return the
+    // experiments it now holds, or `None` if nothing changed since `state` was
+    // last synced. Clients which can't fetch only what changed just fetch
+    // everything, which is what this default implementation does.
+    fn sync_experiments(&self, state: &mut CollectionState) -> Result<Option<Vec<Experiment>>> {
+        *state = Default::default();
+        Ok(Some(self.fetch_experiments()?))
+    }
+
+    // The time until which the server asked us not to make requests, if it did,
+    // so that it can be persisted and handed to a new client after a restart.
+    // Clients which don't talk to a server never need to back off.
+    fn backoff_deadline(&self) -> Option<SystemTime> {
+        None
+    }
+    fn set_backoff_deadline(&self, _deadline: Option<SystemTime>) {}
+}
+
+/// Metadata about the collection of experiments, which is enough to tell whether
+/// it changed without downloading it.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ExperimentsMetadata {
+    /// The timestamp of the last change to the records of the collection.
+    pub last_modified: Option<u64>,
+    /// The number of records in the collection.
+    pub record_count: Option<u64>,
+    /// The signature of the collection, if it's signed.
+    pub signature: Option<CollectionSignature>,
+}
+
+/// The content signature of a collection, and where to find the certificate
+/// chain it was made with.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct CollectionSignature {
+    pub signature: String,
+    pub x5u: String,
+}
+
+/// The records of the experiments collection as we last synced them, persisted
+/// so that the next sync only needs to fetch what changed since.
+///
+/// We keep the raw records, rather than the experiments we parsed from them,
+/// so that a change to (or the deletion of) a record we couldn't parse, eg,
+/// because of an unsupported schema version, is applied correctly.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+pub(crate) struct CollectionState {
+    // The timestamp of the collection when it was last synced, if known.
+    pub last_modified: Option<u64>,
+    // The records of the collection, by id.
+    pub records: BTreeMap<String, serde_json::Value>,
+}
+
+impl CollectionState {
+    // Apply a record which was added or changed since we last synced, or the
+    // tombstone of one which was deleted.
+    pub fn apply_change(&mut self, record: &serde_json::Value) {
+        let id = match record.get("id").and_then(|id| id.as_str()) {
+            Some(id) => id.to_owned(),
+            None => {
+                log::warn!("Ignoring a record without an id");
+                return;
+            }
+        };
+        if record.get("deleted").and_then(|deleted| deleted.as_bool()) == Some(true) {
+            self.records.remove(&id);
+        } else {
+            self.records.insert(id, record.clone());
+        }
+    }
 }
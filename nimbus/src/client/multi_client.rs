@@ -0,0 +1,191 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A SettingsClient which fetches experiments from several collections, eg,
+//! a "preview" collection used for QA on top of the production one.
+//!
+//! Unlike the client for a single collection, it doesn't sync incrementally:
+//! every fetch downloads all the collections in full.
+
+use crate::error::Result;
+use crate::{Experiment, ExperimentsMetadata, SettingsClient};
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+pub struct MultiClient {
+    // The clients for each collection, in increasing order of precedence.
+    clients: Vec<Box<dyn SettingsClient>>,
+}
+
+impl MultiClient {
+    pub fn new(clients: Vec<Box<dyn SettingsClient>>) -> Self {
+        Self { clients }
+    }
+}
+
+impl SettingsClient for MultiClient {
+    // There's no single signature for all the collections, and we only know how
+    // many records there are if we know it for each of them.
+    fn get_experiments_metadata(&self) -> Result<ExperimentsMetadata> {
+        let mut last_modified = None;
+        let mut record_count = Some(0);
+        for client in &self.clients {
+            let metadata = client.get_experiments_metadata()?;
+            last_modified = last_modified.max(metadata.last_modified);
+            record_count = match (record_count, metadata.record_count) {
+                (Some(total), Some(count)) => Some(total + count),
+                _ => None,
+            };
+        }
+        Ok(ExperimentsMetadata {
+            last_modified,
+            record_count,
+            signature: None,
+        })
+    }
+
+    // An experiment replaces the one with the same slug from the collections
+    // with a lower precedence, in its place. If fetching any of the collections
+    // fails, we fail too, rather than risk using experiments which should have
+    // been overridden.
+    fn fetch_experiments(&self) -> Result<Vec<Experiment>> {
+        let mut experiments: Vec<Experiment> = Vec::new();
+        let mut indexes: HashMap<String, usize> = HashMap::new();
+        for client in &self.clients {
+            for experiment in client.fetch_experiments()? {
+                match indexes.get(&experiment.slug) {
+                    Some(&index) => experiments[index] = experiment,
+                    None => {
+                        indexes.insert(experiment.slug.clone(), experiments.len());
+                        experiments.push(experiment);
+                    }
+                }
+            }
+        }
+        Ok(experiments)
+    }
+
+    // All the collections come from the same server, so we back off until the
+    // latest deadline any of them was given.
+    fn backoff_deadline(&self) -> Option<SystemTime> {
+        self.clients
+            .iter()
+            .filter_map(|client| client.backoff_deadline())
+            .max()
+    }
+
+    fn set_backoff_deadline(&self, deadline: Option<SystemTime>) {
+        for client in &self.clients {
+            client.set_backoff_deadline(deadline);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::NimbusError;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    struct TestClient {
+        experiments: Result<Vec<Experiment>, ()>,
+        backoff_deadline: Mutex<Option<SystemTime>>,
+    }
+
+    impl TestClient {
+        fn boxed(experiments: Result<Vec<Experiment>, ()>) -> Box<dyn SettingsClient> {
+            Box::new(Self {
+                experiments,
+                backoff_deadline: Mutex::new(None),
+            })
+        }
+    }
+
+    impl SettingsClient for TestClient {
+        fn get_experiments_metadata(&self) -> Result<ExperimentsMetadata> {
+            Ok(ExperimentsMetadata {
+                last_modified: Some(10 * self.experiments.as_ref().unwrap().len() as u64),
+                record_count: Some(self.experiments.as_ref().unwrap().len() as u64),
+                signature: None,
+            })
+        }
+        fn fetch_experiments(&self) -> Result<Vec<Experiment>> {
+            self.experiments
+                .clone()
+                .map_err(|_| NimbusError::ResponseError("Boom!".into()))
+        }
+        fn backoff_deadline(&self) -> Option<SystemTime> {
+            *self.backoff_deadline.lock().unwrap()
+        }
+        fn set_backoff_deadline(&self, deadline: Option<SystemTime>) {
+            *self.backoff_deadline.lock().unwrap() = deadline
+        }
+    }
+
+    fn experiment(slug: &str, user_facing_name: &str) -> Experiment {
+        Experiment {
+            slug: slug.to_string(),
+            user_facing_name: user_facing_name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_precedence() -> Result<()> {
+        let client = MultiClient::new(vec![
+            TestClient::boxed(Ok(vec![
+                experiment("a", "production"),
+                experiment("b", "production"),
+            ])),
+            TestClient::boxed(Ok(vec![
+                experiment("c", "preview"),
+                experiment("a", "preview"),
+            ])),
+        ]);
+        assert_eq!(
+            client.fetch_experiments()?,
+            vec![
+                experiment("a", "preview"),
+                experiment("b", "production"),
+                experiment("c", "preview"),
+            ]
+        );
+        assert_eq!(
+            client.get_experiments_metadata()?,
+            ExperimentsMetadata {
+                last_modified: Some(20),
+                record_count: Some(4),
+                signature: None,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_failure() {
+        let client = MultiClient::new(vec![
+            TestClient::boxed(Ok(vec![experiment("a", "production")])),
+            TestClient::boxed(Err(())),
+        ]);
+        assert!(matches!(
+            client.fetch_experiments(),
+            Err(NimbusError::ResponseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_backoff_deadline() {
+        let client = MultiClient::new(vec![
+            TestClient::boxed(Ok(vec![])),
+            TestClient::boxed(Ok(vec![])),
+        ]);
+        assert_eq!(client.backoff_deadline(), None);
+        let deadline = SystemTime::now() + Duration::from_secs(60);
+        client.clients[1].set_backoff_deadline(Some(deadline));
+        assert_eq!(client.backoff_deadline(), Some(deadline));
+        client.set_backoff_deadline(None);
+        assert_eq!(client.clients[1].backoff_deadline(), None);
+    }
+}
@@ -3,7 +3,7 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use crate::error::Result;
-use crate::{Experiment, SettingsClient};
+use crate::{Experiment, ExperimentsMetadata, SettingsClient};
 
 /// This is a client for use when no server is provided.
 /// Its primary use is for non-Mozilla forks of apps that are not using their
@@ -17,8 +17,11 @@ impl NullClient {
 }
 
 impl SettingsClient for NullClient {
-    fn get_experiments_metadata(&self) -> Result<String> {
-        unimplemented!();
+    fn get_experiments_metadata(&self) -> Result<ExperimentsMetadata> {
+        Ok(ExperimentsMetadata {
+            record_count: Some(0),
+            ..Default::default()
+        })
     }
     fn fetch_experiments(&self) -> Result<Vec<Experiment>> {
         Ok(Default::default())
@@ -36,7 +39,7 @@ fn test_null_client() -> Result<()> {
     let tmp_dir = TempDir::new("test_null_client-test_null")?;
 
     let aru = Default::default();
-    let client = NimbusClient::new(Default::default(), tmp_dir.path(), None, aru)?;
+    let client = NimbusClient::new(Default::default(), tmp_dir.path(), None, aru, None)?;
     client.fetch_experiments()?;
     client.apply_pending_experiments()?;
 
@@ -0,0 +1,411 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Verification of the content signatures of Remote Settings collections.
+//!
+//! A collection is signed by signing the canonical JSON serialization of its
+//! records and timestamp with the key of the leaf certificate of a chain which
+//! is published at the `x5u` URL of the signature, and which must lead to a
+//! root we trust.
+//! See https://github.com/mozilla-services/autograph/blob/main/signer/contentsignaturepki/README.md
+
+use crate::error::{NimbusError, Result};
+use ring::signature;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use url::Url;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::pem::{parse_x509_pem, Pem};
+
+/// The SHA-256 hash of the root certificate of the chains Remote Settings
+/// collections are signed with. This is the default value of the
+/// `security.content.signature.root_hash` pref in Firefox.
+pub(crate) const MOZILLA_ROOT_HASH: &str =
+    "97e8ba9cf12fb3de53cc42a4e6577ed64df493c247b414fea036818d3823560e";
+
+/// The SHA-256 hash of the root certificate of the chains the collections of
+/// Mozilla's staging and development servers are signed with.
+pub(crate) const STAGE_ROOT_HASH: &str =
+    "3c01446abe9036cea9a09acaa3a520ac628f20a7ae32ce861cb2efb70fa0c745";
+
+/// The hosts of the servers whose collections are signed with the staging root.
+const STAGE_HOSTS: &[&str] = &[
+    "settings.stage.mozaws.net",
+    "settings-cdn.stage.mozaws.net",
+    "settings.dev.mozaws.net",
+    "firefox.settings.services.allizom.org",
+    "remote-settings-dev.allizom.org",
+];
+
+/// The hash of the root we trust to sign the collections of the server at
+/// `server_url`: the staging root for Mozilla's staging and development
+/// servers, and the production one for any other.
+pub(crate) fn root_hash_for(server_url: &Url) -> &'static str {
+    match server_url.host_str() {
+        Some(host) if STAGE_HOSTS.contains(&host) => STAGE_ROOT_HASH,
+        _ => MOZILLA_ROOT_HASH,
+    }
+}
+
+/// Whether `root_hash` looks like a SHA-256 hash in hex, as configured roots must.
+pub(crate) fn is_valid_root_hash(root_hash: &str) -> bool {
+    root_hash.len() == 64 && root_hash.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// The name the leaf certificate must be issued for.
+const SIGNER_NAME: &str = "remote-settings.content-signature.mozilla.org";
+
+/// What gets prepended to the payload before it's signed.
+const SIGNATURE_PREFIX: &[u8] = b"Content-Signature:\x00";
+
+/// The payload which is signed for a collection: its records, sorted by id and
+/// without tombstones, and its timestamp.
+pub(crate) fn collection_payload<'a>(
+    records: impl IntoIterator<Item = &'a serde_json::Value>,
+    last_modified: u64,
+) -> String {
+    let mut records: Vec<_> = records.into_iter().collect();
+    records.sort_by(|a, b| record_id(a).cmp(&record_id(b)));
+    let mut payload = String::new();
+    payload.push_str("{\"data\":[");
+    for (i, record) in records.into_iter().enumerate() {
+        if i > 0 {
+            payload.push(',');
+        }
+        write_canonical_json(&mut payload, record);
+    }
+    payload.push_str("],\"last_modified\":\"");
+    payload.push_str(&last_modified.to_string());
+    payload.push_str("\"}");
+    payload
+}
+
+fn record_id(record: &serde_json::Value) -> Option<&str> {
+    record.get("id").and_then(|id| id.as_str())
+}
+
+// Canonical JSON has no whitespace, sorts the keys of objects and escapes all
+// the characters which aren't printable ASCII.
+fn write_canonical_json(out: &mut String, value: &serde_json::Value) {
+    use serde_json::Value;
+    match value {
+        Value::Null | Value::Bool(_) => out.push_str(&value.to_string()),
+        Value::Number(n) => match n.as_f64() {
+            // Floats which are integers are written without a fractional part,
+            // the way JavaScript does.
+            Some(f) if !(n.is_i64() || n.is_u64()) && f.fract() == 0.0 && f.abs() < 1e21 => {
+                out.push_str(&(f as i64).to_string())
+            }
+            _ => out.push_str(&n.to_string()),
+        },
+        Value::String(s) => write_canonical_string(out, s),
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical_json(out, item);
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            out.push('{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical_string(out, key);
+                out.push(':');
+                write_canonical_json(out, value);
+            }
+            out.push('}');
+        }
+    }
+}
+
+fn write_canonical_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            ' '..='~' => out.push(c),
+            _ => {
+                let mut units = [0; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    // Writing to a `String` can't fail.
+                    let _ = write!(out, "\\u{:04x}", unit);
+                }
+            }
+        }
+    }
+    out.push('"');
+}
+
+fn signature_error(message: impl Into<String>) -> NimbusError {
+    NimbusError::SignatureError(message.into())
+}
+
+/// Verify that `signature` is a signature of `payload` made with the leaf of
+/// the PEM-encoded certificate chain `chain`, and that the chain is valid and
+/// leads to the root whose SHA-256 hash (in hex) is `root_hash`.
+pub(crate) fn verify_signature(
+    payload: &[u8],
+    signature: &str,
+    chain: &[u8],
+    root_hash: &str,
+) -> Result<()> {
+    let pems = parse_pem_chain(chain)?;
+    let certs = pems
+        .iter()
+        .map(|pem| {
+            pem.parse_x509()
+                .map_err(|e| signature_error(format!("Invalid certificate: {}", e)))
+        })
+        .collect::<Result<Vec<X509Certificate<'_>>>>()?;
+    let (leaf, root) = match (certs.first(), certs.last()) {
+        (Some(leaf), Some(root)) => (leaf, root),
+        _ => return Err(signature_error("Empty certificate chain")),
+    };
+
+    // The chain must lead to the root we trust...
+    let hash = Sha256::digest(&pems[pems.len() - 1].contents);
+    if !hex::encode(hash).eq_ignore_ascii_case(root_hash) {
+        return Err(signature_error("Untrusted root certificate"));
+    }
+    // ...with each certificate being valid and issued by the next one, which
+    // must be allowed to issue certificates.
+    for (i, cert) in certs.iter().enumerate() {
+        if !cert.validity().is_valid() {
+            return Err(signature_error("Certificate expired or not yet valid"));
+        }
+        if i > 0 && !is_ca(cert) {
+            return Err(signature_error(
+                "Certificate issued by a non-CA certificate",
+            ));
+        }
+        let issuer = certs.get(i + 1).unwrap_or(root);
+        cert.verify_signature(Some(issuer.public_key()))
+            .map_err(|e| signature_error(format!("Invalid certificate chain: {}", e)))?;
+    }
+    if !is_code_signing(leaf) {
+        return Err(signature_error("Certificate not issued for code signing"));
+    }
+    let is_for_signer = match leaf.tbs_certificate.subject_alternative_name() {
+        Some((_, san)) => san
+            .general_names
+            .iter()
+            .any(|name| matches!(name, GeneralName::DNSName(name) if *name == SIGNER_NAME)),
+        None => false,
+    };
+    if !is_for_signer {
+        return Err(signature_error(format!(
+            "Certificate not issued for {}",
+            SIGNER_NAME
+        )));
+    }
+
+    // Signatures are the base64url-encoded concatenation of the two integers
+    // of the ECDSA P-384 signature.
+    let decoded = base64::decode_config(signature.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+        .map_err(|e| signature_error(format!("Invalid signature encoding: {}", e)))?;
+    let mut message = SIGNATURE_PREFIX.to_vec();
+    message.extend_from_slice(payload);
+    signature::UnparsedPublicKey::new(
+        &signature::ECDSA_P384_SHA384_FIXED,
+        leaf.public_key().subject_public_key.data,
+    )
+    .verify(&message, &decoded)
+    .map_err(|_| signature_error("Signature mismatch"))
+}
+
+// Whether `cert` may issue other certificates: it must be a CA, and its key
+// usage (if any) must allow it.
+fn is_ca(cert: &X509Certificate<'_>) -> bool {
+    let tbs = &cert.tbs_certificate;
+    let is_ca = matches!(tbs.basic_constraints(), Some((_, constraints)) if constraints.ca);
+    let can_sign_certs = match tbs.key_usage() {
+        Some((_, key_usage)) => key_usage.key_cert_sign(),
+        None => true,
+    };
+    is_ca && can_sign_certs
+}
+
+// Whether `cert` may sign content: its key usage (if any) must allow digital
+// signatures, and its extended key usage must include code signing, as
+// Firefox requires of content signatures.
+fn is_code_signing(cert: &X509Certificate<'_>) -> bool {
+    let tbs = &cert.tbs_certificate;
+    let can_sign = match tbs.key_usage() {
+        Some((_, key_usage)) => key_usage.digital_signature(),
+        None => true,
+    };
+    let is_for_code_signing =
+        matches!(tbs.extended_key_usage(), Some((_, usage)) if usage.code_signing);
+    can_sign && is_for_code_signing
+}
+
+fn parse_pem_chain(mut chain: &[u8]) -> Result<Vec<Pem>> {
+    let mut pems = Vec::new();
+    while chain.iter().any(|b| !b.is_ascii_whitespace()) {
+        let (rest, pem) = parse_x509_pem(chain)
+            .map_err(|e| signature_error(format!("Invalid certificate chain: {}", e)))?;
+        pems.push(pem);
+        chain = rest;
+    }
+    Ok(pems)
+}
+
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, ECDSA_P384_SHA384_FIXED_SIGNING};
+
+    /// The chain in `tests/fixtures/content-signature`, and its root's hash.
+    pub const TEST_CHAIN: &str = include_str!("../../tests/fixtures/content-signature/chain.pem");
+    pub const TEST_ROOT_HASH: &str =
+        "299c6293597511ad90f87d1d859b8257340cb2fbb94382a8c5a6f5a078e52a86";
+
+    /// Chains in `tests/fixtures/content-signature` whose leaf has the key of
+    /// `TEST_CHAIN`'s, but which are invalid in other ways, and their root's hash.
+    pub const NON_CA_INTERMEDIATE_CHAIN: &str =
+        include_str!("../../tests/fixtures/content-signature/non-ca-intermediate.pem");
+    pub const NO_CODE_SIGNING_CHAIN: &str =
+        include_str!("../../tests/fixtures/content-signature/no-code-signing.pem");
+    pub const INVALID_CHAINS_ROOT_HASH: &str =
+        "38d85e8c888f06a62fe6858a62130383d97fab3bdd38040414605bb63d231358";
+
+    /// Sign `payload` the way Remote Settings does, with the leaf of `TEST_CHAIN`.
+    pub fn sign(payload: &str) -> String {
+        let key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P384_SHA384_FIXED_SIGNING,
+            include_bytes!("../../tests/fixtures/content-signature/leaf.pk8"),
+        )
+        .unwrap();
+        let mut message = SIGNATURE_PREFIX.to_vec();
+        message.extend_from_slice(payload.as_bytes());
+        let signature = key.sign(&SystemRandom::new(), &message).unwrap();
+        base64::encode_config(signature.as_ref(), base64::URL_SAFE_NO_PAD)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_utils::*;
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_collection_payload() {
+        let records = vec![
+            json!({"id": "b", "last_modified": 20, "float": 1.0, "list": [1.5, null, true]}),
+            json!({"id": "a", "last_modified": 10, "text": "é \"quoted\"\n😀"}),
+        ];
+        assert_eq!(
+            collection_payload(&records, 20),
+            r#"{"data":[{"id":"a","last_modified":10,"text":"\u00e9 \"quoted\"\n\ud83d\ude00"},{"float":1,"id":"b","last_modified":20,"list":[1.5,null,true]}],"last_modified":"20"}"#
+        );
+        assert_eq!(
+            collection_payload(&[], 30),
+            r#"{"data":[],"last_modified":"30"}"#
+        );
+    }
+
+    #[test]
+    fn test_root_hash_for() {
+        let root_hash = |url| root_hash_for(&Url::parse(url).unwrap());
+        assert_eq!(
+            root_hash("https://firefox.settings.services.mozilla.com/v1"),
+            MOZILLA_ROOT_HASH
+        );
+        assert_eq!(
+            root_hash("https://settings.stage.mozaws.net/v1"),
+            STAGE_ROOT_HASH
+        );
+        assert_eq!(
+            root_hash("https://settings.dev.mozaws.net/v1"),
+            STAGE_ROOT_HASH
+        );
+        // Servers we don't know about must be signed like production.
+        assert_eq!(root_hash("https://example.com/v1"), MOZILLA_ROOT_HASH);
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let payload = collection_payload(&[json!({"id": "a", "last_modified": 10})], 10);
+        let signature = sign(&payload);
+        verify_signature(
+            payload.as_bytes(),
+            &signature,
+            TEST_CHAIN.as_bytes(),
+            TEST_ROOT_HASH,
+        )
+        .unwrap();
+
+        // A tampered payload...
+        let tampered = collection_payload(&[json!({"id": "a", "last_modified": 11})], 10);
+        assert!(matches!(
+            verify_signature(
+                tampered.as_bytes(),
+                &signature,
+                TEST_CHAIN.as_bytes(),
+                TEST_ROOT_HASH
+            ),
+            Err(NimbusError::SignatureError(_))
+        ));
+        // ...or a chain we don't trust are rejected.
+        assert!(matches!(
+            verify_signature(
+                payload.as_bytes(),
+                &signature,
+                TEST_CHAIN.as_bytes(),
+                MOZILLA_ROOT_HASH
+            ),
+            Err(NimbusError::SignatureError(_))
+        ));
+        // So is an incomplete chain, where the leaf is its own root.
+        let leaf = &TEST_CHAIN[..TEST_CHAIN.find("-----END CERTIFICATE-----").unwrap() + 25];
+        assert!(matches!(
+            verify_signature(
+                payload.as_bytes(),
+                &signature,
+                leaf.as_bytes(),
+                TEST_ROOT_HASH
+            ),
+            Err(NimbusError::SignatureError(_))
+        ));
+        // So are chains with an intermediate which isn't a CA, or whose leaf
+        // isn't for code signing.
+        for chain in &[NON_CA_INTERMEDIATE_CHAIN, NO_CODE_SIGNING_CHAIN] {
+            assert!(matches!(
+                verify_signature(
+                    payload.as_bytes(),
+                    &signature,
+                    chain.as_bytes(),
+                    INVALID_CHAINS_ROOT_HASH
+                ),
+                Err(NimbusError::SignatureError(_))
+            ));
+        }
+        assert!(matches!(
+            verify_signature(
+                payload.as_bytes(),
+                "garbage!",
+                TEST_CHAIN.as_bytes(),
+                TEST_ROOT_HASH
+            ),
+            Err(NimbusError::SignatureError(_))
+        ));
+    }
+}
@@ -12,9 +12,27 @@
 /// Currently includes the following:
 /// - `server_url`: The url for the settings server that would be used to retrieve experiments
 /// - `bucket_name`: The name of the bucket containing the collection on the server
+/// - `collection_name`: The name of the collection of experiments
+/// - `override_collections`: Other collections on the same server, eg, a "preview"
+///   collection for QA, whose experiments replace the ones with the same slug
+///   from `collection_name` and the collections before them in the list. They
+///   aren't supported for `file://` URLs.
+/// - `root_hash`: The SHA-256 hash (in hex) of the root certificate of the chains
+///   we trust to sign the collections, for servers which don't use Mozilla's.
+///   By default, that's Mozilla's staging root for its staging and development
+///   servers, and its production root for any other server.
 #[derive(Debug, Clone)]
 pub struct RemoteSettingsConfig {
     pub server_url: String,
     pub bucket_name: String,
     pub collection_name: String,
+    pub override_collections: Vec<RemoteSettingsCollection>,
+    pub root_hash: Option<String>,
+}
+
+/// A collection on the server of a `RemoteSettingsConfig`.
+#[derive(Debug, Clone)]
+pub struct RemoteSettingsCollection {
+    pub bucket_name: String,
+    pub collection_name: String,
 }
@@ -4,20 +4,85 @@
 
 use crate::enrollment::get_enrollments;
 use crate::error::{NimbusError, Result};
-use crate::persistence::{Database, Writer};
+use crate::persistence::{write_file_atomically, Database, Readable, StoreId, Writer};
+use crate::{Branch, EnrolledExperiment, Experiment};
+use serde_derive::*;
 use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::RwLock;
 
 // This module manages an in-memory cache of the database, so that some
 // functions exposed by nimbus can return results without blocking on any
 // IO. Consumers are expected to call our public `update()` function whenever
 // the database might have changed.
+//
+// With the LMDB backend, several clients (typically in different processes)
+// may share the same database. Each commit made through the cache increments a
+// "generation" counter in the database, which lets each client detect that
+// another one has changed the database since it last updated its own cache.
+//
+// The enrolled experiments are also written to a small snapshot file next to
+// the database each time the cache is updated, and read back when the cache is
+// created, so that the non-blocking functions about enrollments can answer as
+// soon as the app starts, before the database itself has been opened.
+
+// The key in the `Meta` store of the generation counter.
+pub(crate) const DB_KEY_GENERATION: &str = "generation";
+
+const CACHE_SNAPSHOT_FILENAME: &str = "cache-snapshot.json";
+// ⚠️ Warning : Increment this whenever the serialized form of `CacheSnapshot`
+// (including `EnrolledExperiment`) changes, so that older snapshots are ignored
+// rather than misread. ⚠️
+const CACHE_SNAPSHOT_VERSION: u32 = 3;
 
 // This struct is the cached data. This is never mutated, but instead
 // recreated every time the cache is updated.
 struct CachedData {
+    // The generation of the database this data was built from.
+    pub generation: u64,
+    // The order in which this cache committed this data, so that data from an
+    // earlier commit never replaces it. Zero for the data from the snapshot.
+    pub sequence: u64,
+    pub enrolled_experiments: Vec<EnrolledExperiment>,
+    // All the experiments we know about. These aren't in the snapshot, so that
+    // it stays small, and are thus `None` until the database has been read.
+    pub experiments: Option<Vec<Experiment>>,
     pub branches_by_experiment: HashMap<String, String>,
-    pub branches_by_feature: HashMap<String, String>,
+    pub experiments_by_feature: HashMap<String, EnrolledExperiment>,
+}
+
+impl CachedData {
+    fn new(
+        generation: u64,
+        sequence: u64,
+        enrolled_experiments: Vec<EnrolledExperiment>,
+        experiments: Option<Vec<Experiment>>,
+    ) -> Self {
+        // Build the new hashmaps.  Note that this is somewhat temporary, is
+        // likely to change when the full FeatureConfig stuff is implemented.
+        // Further, note that, for the moment, we only (currently) support
+        // one feature_id per experiment, meaning that we ignore everything
+        // except the first feature_id in the array.  Some of the multi-feature
+        // code may want to live in the EnrollmentEvolver.
+        let mut branches_by_experiment = HashMap::with_capacity(enrolled_experiments.len());
+        let mut experiments_by_feature = HashMap::with_capacity(enrolled_experiments.len());
+
+        for e in &enrolled_experiments {
+            branches_by_experiment.insert(e.slug.clone(), e.branch_slug.clone());
+            experiments_by_feature.insert(e.feature_ids[0].clone(), e.clone());
+        }
+
+        Self {
+            generation,
+            sequence,
+            enrolled_experiments,
+            experiments,
+            branches_by_experiment,
+            experiments_by_feature,
+        }
+    }
 }
 
 // This is the public cache API. Each NimbusClient can create one of these and
@@ -26,11 +91,43 @@ struct CachedData {
 #[derive(Default)]
 pub struct DatabaseCache {
     data: RwLock<Option<CachedData>>,
+    // Where we keep the snapshot of the cached data, if anywhere.
+    snapshot_path: Option<PathBuf>,
+    // The sequence number of the last commit made through this cache.
+    last_sequence: AtomicU64,
+}
+
+// The contents of the snapshot file: the enrolled experiments, from which the
+// maps used by the getters are rebuilt.
+#[derive(Deserialize, Serialize)]
+struct CacheSnapshot<T> {
+    version: u32,
+    generation: u64,
+    enrolled_experiments: T,
 }
 
 impl DatabaseCache {
+    // Create a cache which is kept in a snapshot file in `db_path`, if given,
+    // and initialize it from that file, if there is any. The snapshot is small,
+    // so reading it is cheap, unlike opening the database.
+    //
+    // The data may be stale if the last process to update it crashed before
+    // writing the snapshot, but it's replaced as soon as the database is
+    // initialized.
+    pub fn new(db_path: Option<&Path>) -> Self {
+        let snapshot_path = db_path.map(|path| path.join(CACHE_SNAPSHOT_FILENAME));
+        Self {
+            data: RwLock::new(snapshot_path.as_deref().and_then(read_snapshot)),
+            snapshot_path,
+            ..Default::default()
+        }
+    }
+
     // Call this function whenever it's possible that anything cached by this
-    // struct (eg, our enrollments) might have changed.
+    // struct (eg, our enrollments) might have changed. It returns whether the
+    // cache was updated, which it isn't when the `writer` hasn't touched the
+    // enrollments or experiments and the cache is already up to date, so that
+    // unchanged data isn't cached (and snapshotted) again.
     //
     // This function must be passed a `&Database` and a `Writer`, which it
     // will commit before updating the in-memory cache. This is a slightly weird
@@ -40,42 +137,124 @@ impl DatabaseCache {
     //    and thus prevent the possibility of caching stale data.
     //  * By taking ownership of the `Writer`, we ensure that the calling code
     //    updates the cache after all of its writes have been performed.
-    pub fn commit_and_update(&self, db: &Database, writer: Writer) -> Result<()> {
-        // By passing in the active `writer` we read the state of enrollments
-        // as written by the calling code, before it's committed to the db.
-        let experiments = get_enrollments(&db, &writer)?;
+    pub fn commit_and_update(&self, db: &Database, mut writer: Writer) -> Result<bool> {
+        if !writer.has_modified(StoreId::Enrollments) && !writer.has_modified(StoreId::Experiments)
+        {
+            if self.is_complete(db, &writer)? {
+                writer.commit()?;
+                return Ok(false);
+            }
+            let generation = get_generation(db, &writer)?;
+            self.commit_and_cache(db, writer, generation)?;
+            return Ok(true);
+        }
+        let meta_store = db.get_store(StoreId::Meta);
+        let generation = get_generation(db, &writer)?.wrapping_add(1);
+        meta_store.put(&mut writer, DB_KEY_GENERATION, &generation)?;
+        self.commit_and_cache(db, writer, generation)?;
+        Ok(true)
+    }
 
-        // Build the new hashmaps.  Note that this is somewhat temporary, is
-        // likely to change when the full FeatureConfig stuff is implemented.
-        // Further, note that, for the moment, we only (currently) support
-        // one feature_id per experiment, meaning that we ignore everything
-        // except the first feature_id in the array.  Some of the multi-feature
-        // code may want to live in the EnrollmentEvolver.
-        let mut branches_by_experiment = HashMap::with_capacity(experiments.len());
-        let mut branches_by_feature = HashMap::with_capacity(experiments.len());
+    // Check whether the database has been changed by another client since we
+    // last updated the cache (or whether the cache was never initialized).
+    pub fn is_stale<'r>(&self, db: &Database, reader: &'r impl Readable<'r>) -> Result<bool> {
+        let generation = get_generation(db, reader)?;
+        let cached = self.data.read().unwrap();
+        Ok(cached.as_ref().map(|data| data.generation) != Some(generation))
+    }
+
+    // Whether the cache is up to date, and was built from the database rather
+    // than from the snapshot, so that it has all the experiments.
+    fn is_complete<'r>(&self, db: &Database, reader: &'r impl Readable<'r>) -> Result<bool> {
+        let generation = get_generation(db, reader)?;
+        let cached = self.data.read().unwrap();
+        Ok(
+            matches!(&*cached, Some(data) if data.generation == generation && data.experiments.is_some()),
+        )
+    }
 
-        for e in experiments {
-            branches_by_experiment.insert(e.slug, e.branch_slug.clone());
-            branches_by_feature.insert(e.feature_ids[0].clone(), e.branch_slug);
+    // Rebuild the cache if it is stale, returning whether it was. Like
+    // `commit_and_update()` this takes a `Writer`, to exclude other writers
+    // while we read, but it doesn't count as a change to the database.
+    pub fn update_if_stale(&self, db: &Database, writer: Writer) -> Result<bool> {
+        if !self.is_stale(db, &writer)? {
+            return Ok(false);
         }
+        let generation = get_generation(db, &writer)?;
+        self.commit_and_cache(db, writer, generation)?;
+        Ok(true)
+    }
 
-        let data = CachedData {
-            branches_by_experiment,
-            branches_by_feature,
-        };
+    fn commit_and_cache(&self, db: &Database, writer: Writer, generation: u64) -> Result<()> {
+        // By passing in the active `writer` we read the state of enrollments
+        // as written by the calling code, before it's committed to the db.
+        let enrolled_experiments = get_enrollments(&db, &writer)?;
+        let experiments: Vec<Experiment> =
+            db.get_store(StoreId::Experiments).collect_all(&writer)?;
+        let sequence = self.last_sequence.fetch_add(1, Ordering::SeqCst) + 1;
+        let data = CachedData::new(
+            generation,
+            sequence,
+            enrolled_experiments,
+            Some(experiments),
+        );
+
+        // The snapshot is written while the `writer` still excludes other
+        // writers, so that snapshots are written in the same order as the
+        // commits, and the last one is always the latest. It's only an
+        // optimization, so failing to write it isn't fatal, but we don't want
+        // to leave one behind for changes which weren't committed.
+        let snapshot_written = self.write_snapshot(&data);
+        if let Err(e) = writer.commit() {
+            if snapshot_written {
+                self.remove_snapshot();
+            }
+            return Err(e);
+        }
 
         // Try to commit the change to disk and update the cache as close
         // together in time as possible. This leaves a small window where another
         // thread could read new data from disk but see old data in the cache,
         // but that seems benign in practice given the way we use the cache.
         // The alternative would be to lock the cache while we commit to disk,
-        // and we don't want to risk blocking the main thread.
-        writer.commit()?;
+        // and we don't want to risk blocking the main thread. Another thread may
+        // also have committed and cached newer data in that window, which we
+        // must then keep.
         let mut cached = self.data.write().unwrap();
-        cached.replace(data);
+        if cached
+            .as_ref()
+            .map_or(true, |cached| cached.sequence < data.sequence)
+        {
+            cached.replace(data);
+        }
         Ok(())
     }
 
+    // Write the snapshot of `data`, if we keep one, returning whether we did.
+    fn write_snapshot(&self, data: &CachedData) -> bool {
+        let path = match &self.snapshot_path {
+            Some(path) => path,
+            None => return false,
+        };
+        match write_snapshot_contents(data)
+            .and_then(|contents| write_file_atomically(path, &contents))
+        {
+            Ok(()) => true,
+            Err(e) => {
+                log::warn!("Failed to write the cache snapshot: {}", e);
+                false
+            }
+        }
+    }
+
+    fn remove_snapshot(&self) {
+        if let Some(path) = &self.snapshot_path {
+            if let Err(e) = fs::remove_file(path) {
+                log::warn!("Failed to remove the cache snapshot: {}", e);
+            }
+        }
+    }
+
     // Abstracts safely referencing our cached data.
     //
     // WARNING: because this manages locking, the callers of this need to be
@@ -98,9 +277,182 @@ impl DatabaseCache {
     }
 
     pub fn get_experiment_branch(&self, id: &str) -> Result<Option<String>> {
-        self.get_data(|data| match data.branches_by_feature.get(id) {
+        self.get_data(|data| match data.experiments_by_feature.get(id) {
             None => data.branches_by_experiment.get(id).cloned(),
-            Some(branch_slug) => Some(branch_slug.to_owned()),
+            Some(experiment) => Some(experiment.branch_slug.to_owned()),
+        })
+    }
+
+    pub fn get_enrolled_experiment_by_feature(
+        &self,
+        feature_id: &str,
+    ) -> Result<Option<EnrolledExperiment>> {
+        self.get_data(|data| data.experiments_by_feature.get(feature_id).cloned())
+    }
+
+    pub fn get_active_experiments(&self) -> Result<Vec<EnrolledExperiment>> {
+        self.get_data(|data| data.enrolled_experiments.clone())
+    }
+
+    // Like `get_data()`, for the experiments which are only known once the
+    // cache has been built from the database rather than from the snapshot.
+    fn get_experiments<T, F>(&self, func: F) -> Result<T>
+    where
+        F: FnOnce(&[Experiment]) -> T,
+    {
+        self.get_data(|data| data.experiments.as_deref().map(func))?
+            .ok_or(NimbusError::DatabaseNotReady)
+    }
+
+    pub fn get_all_experiments(&self) -> Result<Vec<Experiment>> {
+        self.get_experiments(|experiments| experiments.to_vec())
+    }
+
+    pub fn get_experiment_branches(&self, slug: &str) -> Result<Option<Vec<Branch>>> {
+        self.get_experiments(|experiments| {
+            experiments
+                .iter()
+                .find(|e| e.slug == slug)
+                .map(|e| e.branches.clone())
         })
     }
 }
+
+fn read_snapshot(path: &Path) -> Option<CachedData> {
+    let contents = fs::read_to_string(path).ok()?;
+    match serde_json::from_str::<CacheSnapshot<Vec<EnrolledExperiment>>>(&contents) {
+        Ok(snapshot) if snapshot.version == CACHE_SNAPSHOT_VERSION => Some(CachedData::new(
+            snapshot.generation,
+            0,
+            snapshot.enrolled_experiments,
+            None,
+        )),
+        Ok(snapshot) => {
+            log::info!("Ignoring cache snapshot version {}", snapshot.version);
+            None
+        }
+        Err(e) => {
+            log::warn!("Ignoring invalid cache snapshot: {}", e);
+            None
+        }
+    }
+}
+
+fn write_snapshot_contents(data: &CachedData) -> Result<String> {
+    Ok(serde_json::to_string(&CacheSnapshot {
+        version: CACHE_SNAPSHOT_VERSION,
+        generation: data.generation,
+        enrolled_experiments: &data.enrolled_experiments,
+    })?)
+}
+
+fn get_generation<'r>(db: &Database, reader: &'r impl Readable<'r>) -> Result<u64> {
+    Ok(db
+        .get_store(StoreId::Meta)
+        .get(reader, DB_KEY_GENERATION)?
+        .unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    // A writer which has written to the experiments, without changing them.
+    fn experiments_writer(db: &Database) -> Result<Writer<'_>> {
+        let mut writer = db.write()?;
+        db.get_store(StoreId::Experiments).clear(&mut writer)?;
+        Ok(writer)
+    }
+
+    #[test]
+    fn test_generation() -> Result<()> {
+        // Two caches over the same database, as two clients sharing it would have.
+        let db = Database::new_in_memory()?;
+        let cache = DatabaseCache::default();
+        let other_cache = DatabaseCache::default();
+        assert!(cache.is_stale(&db, &db.read()?)?);
+
+        // Building the cache doesn't count as a change...
+        assert!(cache.commit_and_update(&db, db.write()?)?);
+        assert!(!cache.is_stale(&db, &db.read()?)?);
+        assert_eq!(get_generation(&db, &db.read()?)?, 0);
+        // ...and neither do writes which don't touch what's cached.
+        assert!(!cache.commit_and_update(&db, db.write()?)?);
+        assert_eq!(get_generation(&db, &db.read()?)?, 0);
+
+        assert!(cache.commit_and_update(&db, experiments_writer(&db)?)?);
+        assert!(!cache.is_stale(&db, &db.read()?)?);
+        assert_eq!(get_generation(&db, &db.read()?)?, 1);
+        assert!(other_cache.is_stale(&db, &db.read()?)?);
+
+        // Catching up doesn't count as a change.
+        assert!(other_cache.update_if_stale(&db, db.write()?)?);
+        assert!(!other_cache.update_if_stale(&db, db.write()?)?);
+        assert!(!cache.is_stale(&db, &db.read()?)?);
+
+        other_cache.commit_and_update(&db, experiments_writer(&db)?)?;
+        assert!(cache.is_stale(&db, &db.read()?)?);
+        assert!(cache.update_if_stale(&db, db.write()?)?);
+        assert_eq!(get_generation(&db, &db.read()?)?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot() -> Result<()> {
+        let tmp_dir = TempDir::new("test_snapshot")?;
+        let db = Database::new_in_memory()?;
+        let cache = DatabaseCache::new(Some(tmp_dir.path()));
+        assert!(cache.data.read().unwrap().is_none());
+        cache.commit_and_update(&db, db.write()?)?;
+
+        let cache = DatabaseCache::new(Some(tmp_dir.path()));
+        assert_eq!(cache.get_experiment_branch("foo")?, None);
+        assert!(cache.get_active_experiments()?.is_empty());
+        assert!(!cache.is_stale(&db, &db.read()?)?);
+        // All the experiments aren't in the snapshot, so they can only be
+        // answered once the cache is built from the database.
+        assert!(matches!(
+            cache.get_all_experiments(),
+            Err(NimbusError::DatabaseNotReady)
+        ));
+        cache.commit_and_update(&db, db.write()?)?;
+        assert!(cache.get_all_experiments()?.is_empty());
+
+        // Snapshots we can't read are ignored.
+        let snapshot_path = tmp_dir.path().join(CACHE_SNAPSHOT_FILENAME);
+        let mut snapshot: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&snapshot_path)?)?;
+        snapshot["version"] = serde_json::json!(CACHE_SNAPSHOT_VERSION + 1);
+        fs::write(&snapshot_path, snapshot.to_string())?;
+        assert!(DatabaseCache::new(Some(tmp_dir.path()))
+            .data
+            .read()
+            .unwrap()
+            .is_none());
+        fs::write(&snapshot_path, "Not a snapshot!")?;
+        assert!(DatabaseCache::new(Some(tmp_dir.path()))
+            .data
+            .read()
+            .unwrap()
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_older_commits_do_not_replace_newer_data() -> Result<()> {
+        let tmp_dir = TempDir::new("test_older_commits_do_not_replace_newer_data")?;
+        let db = Database::new_in_memory()?;
+        let cache = DatabaseCache::new(Some(tmp_dir.path()));
+        cache.commit_and_update(&db, experiments_writer(&db)?)?;
+        // Eg, another thread cached the data of a later commit before this one
+        // could.
+        cache.data.write().unwrap().as_mut().unwrap().sequence += 1;
+        cache.commit_and_update(&db, experiments_writer(&db)?)?;
+        assert_eq!(cache.data.read().unwrap().as_ref().unwrap().generation, 1);
+        // The snapshot is from the latest commit though.
+        let snapshot = read_snapshot(&tmp_dir.path().join(CACHE_SNAPSHOT_FILENAME)).unwrap();
+        assert_eq!(snapshot.generation, 2);
+        Ok(())
+    }
+}
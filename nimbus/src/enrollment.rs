@@ -2,20 +2,26 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 use crate::error::{NimbusError, Result};
-use crate::persistence::{Database, StoreId, Writer};
-use crate::{evaluator::evaluate_enrollment, persistence::Readable};
+use crate::persistence::{Database, SingleStore, StoreId, Writer};
+use crate::{evaluator::evaluate_enrollment, persistence::Readable, sampling};
 use crate::{AppContext, AvailableRandomizationUnits, EnrolledExperiment, Experiment};
 
 use ::uuid::Uuid;
 use serde_derive::*;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-const DB_KEY_GLOBAL_USER_PARTICIPATION: &str = "user-opt-in";
+pub(crate) const DB_KEY_GLOBAL_USER_PARTICIPATION: &str = "user-opt-in";
+pub(crate) const DB_KEY_EXPERIMENT_OPT_OUTS: &str = "opted-out-experiments";
+// Where versions which didn't garbage collect opt-outs kept them, as a list of
+// slugs. We only read it, for databases which don't have the new key yet, so that
+// those versions still find it as they left it after a downgrade.
+pub(crate) const DB_KEY_LEGACY_EXPERIMENT_OPT_OUTS: &str = "experiment-opt-outs";
 const DEFAULT_GLOBAL_USER_PARTICIPATION: bool = true;
-const PREVIOUS_ENROLLMENTS_GC_TIME: Duration = Duration::from_secs(30 * 24 * 3600);
+pub(crate) const DEFAULT_PREVIOUS_ENROLLMENTS_GC_TIME: Duration =
+    Duration::from_secs(30 * 24 * 3600);
 
 // These are types we use internally for managing enrollments.
 // ⚠️ Attention : Changes to this type should be accompanied by a new test  ⚠️
@@ -36,6 +42,7 @@ pub enum NotEnrolledReason {
     NotSelected, // The evaluator bucketing did not choose us.
     NotTargeted, // We are not being targeted for this experiment.
     EnrollmentsPaused, // The experiment enrollment is paused.
+    TooManyExperiments, // We qualified, but were already in as many experiments as allowed.
 }
 
 // These are types we use internally for managing disqualifications.
@@ -49,6 +56,44 @@ pub enum DisqualifiedReason {
     NotTargeted, // The targeting has changed for an experiment.
 }
 
+impl DisqualifiedReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DisqualifiedReason::NotTargeted => "targeting",
+            DisqualifiedReason::OptOut => "optout",
+            DisqualifiedReason::Error => "error",
+        }
+    }
+}
+
+// These are types we use internally for managing errors evaluating an experiment.
+
+// ⚠️ Attention : Changes to this type should be accompanied by a new test  ⚠️
+// ⚠️ in `mod test_schema_bw_compat` below, and may require a DB migration. ⚠️
+#[derive(Deserialize, Serialize, Debug, Clone, Hash, Eq, PartialEq)]
+pub enum EnrollmentErrorReason {
+    MissingRandomizationUnit, // None of the available randomization units matched the experiment's.
+    InvalidTargeting,         // The targeting expression did not evaluate to a bool.
+    EvaluationError(String),  // The targeting expression failed to evaluate.
+    SamplingFailure(String),  // Bucketing or branch selection failed.
+    Unknown(String),          // A reason persisted by an older version which we could not map.
+}
+
+impl EnrollmentErrorReason {
+    /// Map the free-form reason strings persisted by DB version 1 to a structured reason.
+    pub(crate) fn from_legacy_reason(reason: &str) -> Self {
+        if reason == "No randomization unit" {
+            EnrollmentErrorReason::MissingRandomizationUnit
+        } else if reason == NimbusError::InvalidExpression.to_string() {
+            EnrollmentErrorReason::InvalidTargeting
+        } else if let Some(detail) = reason.strip_prefix("EvaluationError: ") {
+            EnrollmentErrorReason::EvaluationError(detail.to_owned())
+        } else {
+            EnrollmentErrorReason::Unknown(reason.to_owned())
+        }
+    }
+}
+
 // Every experiment has an ExperimentEnrollment, even when we aren't enrolled.
 
 // ⚠️ Attention : Changes to this type should be accompanied by a new test  ⚠️
@@ -134,6 +179,7 @@ impl ExperimentEnrollment {
         nimbus_id: &Uuid,
         available_randomization_units: &AvailableRandomizationUnits,
         app_context: &AppContext,
+        existing_experiment: &Experiment,
         updated_experiment: &Experiment,
         out_enrollment_events: &mut Vec<EnrollmentChangeEvent>,
     ) -> Result<Self> {
@@ -160,7 +206,11 @@ impl ExperimentEnrollment {
                     updated_enrollment
                 }
             }
-            EnrollmentStatus::Enrolled { ref branch, .. } => {
+            EnrollmentStatus::Enrolled {
+                ref branch,
+                ref reason,
+                ..
+            } => {
                 if !is_user_participating {
                     log::debug!(
                         "Existing experiment enrollment '{}' is now disqualified (global opt-out)",
@@ -176,6 +226,11 @@ impl ExperimentEnrollment {
                         self.disqualify_from_enrolled(DisqualifiedReason::Error);
                     out_enrollment_events.push(updated_enrollment.get_change_event());
                     updated_enrollment
+                } else if *reason == EnrolledReason::OptIn {
+                    // The user explicitly chose this branch, so targeting and bucketing
+                    // no longer apply; only opting out or the experiment (or branch)
+                    // going away will end this enrollment.
+                    self.clone()
                 } else {
                     let evaluated_enrollment = evaluate_enrollment(
                         nimbus_id,
@@ -228,7 +283,28 @@ impl ExperimentEnrollment {
                     self.clone()
                 }
             }
-            EnrollmentStatus::WasEnrolled { .. } | EnrollmentStatus::Error { .. } => self.clone(), // Cannot recover from errors!
+            EnrollmentStatus::Error { .. } => {
+                if updated_experiment == existing_experiment {
+                    // Evaluating the same experiment again would give us the same error.
+                    self.clone()
+                } else {
+                    // The experiment has changed (eg, its broken targeting was fixed),
+                    // so evaluate it again as if we were seeing it for the first time.
+                    log::debug!(
+                        "Experiment '{}' changed since we errored, re-evaluating",
+                        &self.slug
+                    );
+                    Self::from_new_experiment(
+                        is_user_participating,
+                        nimbus_id,
+                        available_randomization_units,
+                        app_context,
+                        updated_experiment,
+                        out_enrollment_events,
+                    )?
+                }
+            }
+            EnrollmentStatus::WasEnrolled { .. } => self.clone(),
         })
     }
 
@@ -236,7 +312,7 @@ impl ExperimentEnrollment {
     /// after an experiment has disappeared from the server.
     ///
     /// If we transitioned to WasEnrolled, our enrollment will be garbage collected
-    /// from the database after `PREVIOUS_ENROLLMENTS_GC_TIME`.
+    /// from the database after the evolver's `previous_enrollments_gc_time`.
     fn on_experiment_ended(
         &self,
         out_enrollment_events: &mut Vec<EnrollmentChangeEvent>,
@@ -333,14 +409,14 @@ impl ExperimentEnrollment {
 
     /// Garbage collect old experiments we've kept a WasEnrolled enrollment from.
     /// Returns Option::None if the enrollment should be nuked from the db.
-    fn maybe_garbage_collect(&self) -> Option<Self> {
+    fn maybe_garbage_collect(&self, gc_time: Duration) -> Option<Self> {
         if let EnrollmentStatus::WasEnrolled {
             experiment_ended_at,
             ..
         } = self.status
         {
             let time_since_transition = Duration::from_secs(now_secs() - experiment_ended_at);
-            if time_since_transition < PREVIOUS_ENROLLMENTS_GC_TIME {
+            if time_since_transition < gc_time {
                 return Some(self.clone());
             }
         }
@@ -383,11 +459,7 @@ impl ExperimentEnrollment {
                 &self.slug,
                 &enrollment_id,
                 &branch,
-                match reason {
-                    DisqualifiedReason::NotTargeted => Some("targeting"),
-                    DisqualifiedReason::OptOut => Some("optout"),
-                    DisqualifiedReason::Error => Some("error"),
-                },
+                Some(reason.as_str()),
                 EnrollmentChangeEventType::Disqualification,
             ),
             EnrollmentStatus::NotEnrolled { .. } | EnrollmentStatus::Error { .. } => unreachable!(),
@@ -445,9 +517,8 @@ pub enum EnrollmentStatus {
     },
     // There was some error opting in.
     Error {
-        // Ideally this would be an Error, but then we'd need to make Error
-        // serde compatible, which isn't trivial nor desirable.
-        reason: String,
+        // Before DB version 2 this was a stringified `NimbusError`.
+        reason: EnrollmentErrorReason,
     },
 }
 
@@ -463,15 +534,12 @@ impl EnrollmentStatus {
         }
     }
 
-    // This is used in examples, but not in the main dylib, and
-    // triggers a dead code warning when building with `--release`.
-    #[allow(dead_code)]
     pub fn is_enrolled(&self) -> bool {
         matches!(self, EnrollmentStatus::Enrolled { .. })
     }
 
     /// Make a clone of this status, but with the special nil enrollment_id.
-    fn clone_with_nil_enrollment_id(&self) -> Self {
+    pub(crate) fn clone_with_nil_enrollment_id(&self) -> Self {
         let mut updated = self.clone();
         match updated {
             EnrollmentStatus::Enrolled {
@@ -532,10 +600,198 @@ pub fn get_enrollments<'r>(
     Ok(result)
 }
 
+/// A flattened view of an `ExperimentEnrollment`, whatever its status,
+/// suitable for passing over the FFI to debugging UIs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExperimentEnrollmentStatus {
+    pub slug: String,
+    pub status: EnrollmentStatusType,
+    pub reason: Option<EnrollmentStatusReason>,
+    pub branch_slug: Option<String>,
+    pub enrollment_id: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EnrollmentStatusType {
+    Enrolled,
+    NotEnrolled,
+    Disqualified,
+    WasEnrolled,
+    Error,
+}
+
+/// The reason of any of the statuses, flattened in a single enum since the
+/// FFI doesn't support enums with data. The details of errors are left out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnrollmentStatusReason {
+    Qualified,
+    OptIn,
+    OptOut,
+    NotSelected,
+    NotTargeted,
+    EnrollmentsPaused,
+    TooManyExperiments,
+    Error,
+    MissingRandomizationUnit,
+    InvalidTargeting,
+    EvaluationError,
+    SamplingFailure,
+    Unknown,
+}
+
+impl From<&EnrolledReason> for EnrollmentStatusReason {
+    fn from(reason: &EnrolledReason) -> Self {
+        match reason {
+            EnrolledReason::Qualified => EnrollmentStatusReason::Qualified,
+            EnrolledReason::OptIn => EnrollmentStatusReason::OptIn,
+        }
+    }
+}
+
+impl From<&NotEnrolledReason> for EnrollmentStatusReason {
+    fn from(reason: &NotEnrolledReason) -> Self {
+        match reason {
+            NotEnrolledReason::OptOut => EnrollmentStatusReason::OptOut,
+            NotEnrolledReason::NotSelected => EnrollmentStatusReason::NotSelected,
+            NotEnrolledReason::NotTargeted => EnrollmentStatusReason::NotTargeted,
+            NotEnrolledReason::EnrollmentsPaused => EnrollmentStatusReason::EnrollmentsPaused,
+            NotEnrolledReason::TooManyExperiments => EnrollmentStatusReason::TooManyExperiments,
+        }
+    }
+}
+
+impl From<&DisqualifiedReason> for EnrollmentStatusReason {
+    fn from(reason: &DisqualifiedReason) -> Self {
+        match reason {
+            DisqualifiedReason::Error => EnrollmentStatusReason::Error,
+            DisqualifiedReason::OptOut => EnrollmentStatusReason::OptOut,
+            DisqualifiedReason::NotTargeted => EnrollmentStatusReason::NotTargeted,
+        }
+    }
+}
+
+impl From<&EnrollmentErrorReason> for EnrollmentStatusReason {
+    fn from(reason: &EnrollmentErrorReason) -> Self {
+        match reason {
+            EnrollmentErrorReason::MissingRandomizationUnit => {
+                EnrollmentStatusReason::MissingRandomizationUnit
+            }
+            EnrollmentErrorReason::InvalidTargeting => EnrollmentStatusReason::InvalidTargeting,
+            EnrollmentErrorReason::EvaluationError(_) => EnrollmentStatusReason::EvaluationError,
+            EnrollmentErrorReason::SamplingFailure(_) => EnrollmentStatusReason::SamplingFailure,
+            EnrollmentErrorReason::Unknown(_) => EnrollmentStatusReason::Unknown,
+        }
+    }
+}
+
+impl From<&ExperimentEnrollment> for ExperimentEnrollmentStatus {
+    fn from(enrollment: &ExperimentEnrollment) -> Self {
+        let (status, reason, branch_slug, enrollment_id) = match &enrollment.status {
+            EnrollmentStatus::Enrolled {
+                enrollment_id,
+                reason,
+                branch,
+                ..
+            } => (
+                EnrollmentStatusType::Enrolled,
+                Some(reason.into()),
+                Some(branch.clone()),
+                Some(enrollment_id.to_string()),
+            ),
+            EnrollmentStatus::NotEnrolled { reason } => (
+                EnrollmentStatusType::NotEnrolled,
+                Some(reason.into()),
+                None,
+                None,
+            ),
+            EnrollmentStatus::Disqualified {
+                enrollment_id,
+                reason,
+                branch,
+            } => (
+                EnrollmentStatusType::Disqualified,
+                Some(reason.into()),
+                Some(branch.clone()),
+                Some(enrollment_id.to_string()),
+            ),
+            EnrollmentStatus::WasEnrolled {
+                enrollment_id,
+                branch,
+                ..
+            } => (
+                EnrollmentStatusType::WasEnrolled,
+                None,
+                Some(branch.clone()),
+                Some(enrollment_id.to_string()),
+            ),
+            EnrollmentStatus::Error { reason } => {
+                (EnrollmentStatusType::Error, Some(reason.into()), None, None)
+            }
+        };
+        ExperimentEnrollmentStatus {
+            slug: enrollment.slug.clone(),
+            status,
+            reason,
+            branch_slug,
+            enrollment_id,
+        }
+    }
+}
+
+/// Return the enrollment status of every experiment we know about,
+/// including those we are not enrolled in.
+pub fn get_enrollment_statuses<'r>(
+    db: &Database,
+    reader: &'r impl Readable<'r>,
+) -> Result<Vec<ExperimentEnrollmentStatus>> {
+    let enrollments: Vec<ExperimentEnrollment> =
+        db.get_store(StoreId::Enrollments).collect_all(reader)?;
+    Ok(enrollments.iter().map(Into::into).collect())
+}
+
+/// An experiment we were enrolled in, which has since ended but whose
+/// enrollment has not been garbage collected yet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreviousExperiment {
+    pub slug: String,
+    pub branch_slug: String,
+    pub enrollment_id: String,
+    pub experiment_ended_at: u64, // unix timestamp in sec
+}
+
+/// Return information about the experiments we were previously enrolled in.
+pub fn get_previous_enrollments<'r>(
+    db: &Database,
+    reader: &'r impl Readable<'r>,
+) -> Result<Vec<PreviousExperiment>> {
+    let enrollments: Vec<ExperimentEnrollment> =
+        db.get_store(StoreId::Enrollments).collect_all(reader)?;
+    Ok(enrollments
+        .into_iter()
+        .filter_map(|enrollment| match enrollment.status {
+            EnrollmentStatus::WasEnrolled {
+                enrollment_id,
+                branch,
+                experiment_ended_at,
+            } => Some(PreviousExperiment {
+                slug: enrollment.slug,
+                branch_slug: branch,
+                enrollment_id: enrollment_id.to_string(),
+                experiment_ended_at,
+            }),
+            _ => None,
+        })
+        .collect())
+}
+
 pub(crate) struct EnrollmentsEvolver<'a> {
     nimbus_id: &'a Uuid,
     available_randomization_units: &'a AvailableRandomizationUnits,
     app_context: &'a AppContext,
+    // The maximum number of experiments we may be enrolled in at once, if any.
+    max_active_enrollments: Option<usize>,
+    // How long we keep `WasEnrolled` enrollments around after their experiment ended.
+    previous_enrollments_gc_time: Duration,
 }
 
 impl<'a> EnrollmentsEvolver<'a> {
@@ -543,60 +799,129 @@ impl<'a> EnrollmentsEvolver<'a> {
         nimbus_id: &'a Uuid,
         available_randomization_units: &'a AvailableRandomizationUnits,
         app_context: &'a AppContext,
+        max_active_enrollments: Option<usize>,
+        previous_enrollments_gc_time: Duration,
     ) -> Self {
         Self {
             nimbus_id,
             available_randomization_units,
             app_context,
+            max_active_enrollments,
+            previous_enrollments_gc_time,
         }
     }
 
-    /// Convenient wrapper around `evolve_enrollments` that fetches the current state of experiments,
-    /// enrollments and user participation from the database.
+    /// Like `evolve_enrollments_in_db`, but only returns the change events that
+    /// would be emitted, without writing anything to the database.
+    pub(crate) fn preview_enrollments_in_db<'r>(
+        &self,
+        db: &Database,
+        reader: &'r impl Readable<'r>,
+        updated_experiments: &[Experiment],
+    ) -> Result<Vec<EnrollmentChangeEvent>> {
+        let existing_experiments: Vec<Experiment> =
+            db.get_store(StoreId::Experiments).collect_all(reader)?;
+        let existing_enrollments: Vec<ExperimentEnrollment> =
+            db.get_store(StoreId::Enrollments).collect_all(reader)?;
+        let (_, enrollments_change_events) = self.evolve_enrollments_from_db(
+            db,
+            reader,
+            &existing_experiments,
+            &existing_enrollments,
+            updated_experiments,
+        )?;
+        Ok(enrollments_change_events)
+    }
+
+    /// Convenient wrapper around `evolve_enrollments` that fetches the user participation
+    /// from the database.
+    fn evolve_enrollments_from_db<'r>(
+        &self,
+        db: &Database,
+        reader: &'r impl Readable<'r>,
+        existing_experiments: &[Experiment],
+        existing_enrollments: &[ExperimentEnrollment],
+        updated_experiments: &[Experiment],
+    ) -> Result<(Vec<ExperimentEnrollment>, Vec<EnrollmentChangeEvent>)> {
+        let is_user_participating = get_global_user_participation(db, reader)?;
+        let opted_out_experiments = get_experiment_opt_outs(db, reader)?;
+        self.evolve_enrollments(
+            is_user_participating,
+            &opted_out_experiments,
+            existing_experiments,
+            updated_experiments,
+            existing_enrollments,
+        )
+    }
+
+    /// Evolve the enrollments in the database against `updated_experiments`, and
+    /// replace the stored experiments with them.
+    ///
+    /// Only the experiments and enrollments which were added, changed or removed
+    /// are written, so applying an unchanged set of experiments writes nothing.
     pub(crate) fn evolve_enrollments_in_db(
         &self,
         db: &Database,
         writer: &mut Writer,
         updated_experiments: &[Experiment],
     ) -> Result<Vec<EnrollmentChangeEvent>> {
-        // Get the state from the db.
-        let is_user_participating = get_global_user_participation(db, writer)?;
         let experiments_store = db.get_store(StoreId::Experiments);
         let enrollments_store = db.get_store(StoreId::Enrollments);
-        let existing_experiments: Vec<Experiment> = experiments_store.collect_all(writer)?;
+        let existing_experiments: Vec<Experiment> = experiments_store.collect_all(&*writer)?;
         let existing_enrollments: Vec<ExperimentEnrollment> =
-            enrollments_store.collect_all(writer)?;
+            enrollments_store.collect_all(&*writer)?;
         // Calculate the changes.
-        let (updated_enrollments, enrollments_change_events) = self.evolve_enrollments(
-            is_user_participating,
+        let (updated_enrollments, enrollments_change_events) = self.evolve_enrollments_from_db(
+            db,
+            &*writer,
             &existing_experiments,
-            updated_experiments,
             &existing_enrollments,
+            updated_experiments,
         )?;
+        let existing_experiments = map_experiments(&existing_experiments);
+        let existing_enrollments = map_enrollments(&existing_enrollments);
+        let updated_experiments = map_experiments(updated_experiments);
         let updated_enrollments = map_enrollments(&updated_enrollments);
-        // Write the changes to the Database.
-        enrollments_store.clear(writer)?;
-        for enrollment in updated_enrollments.values() {
-            enrollments_store.put(writer, &enrollment.slug, *enrollment)?;
-        }
-        experiments_store.clear(writer)?;
-        for experiment in updated_experiments {
-            // Sanity check.
-            if !updated_enrollments.contains_key(&experiment.slug) {
-                return Err(NimbusError::InternalError(
-                    "An experiment must always have an associated enrollment.",
-                ));
-            }
-            experiments_store.put(writer, &experiment.slug, experiment)?;
+        // Sanity check, before we write anything.
+        if updated_experiments
+            .keys()
+            .any(|slug| !updated_enrollments.contains_key(slug))
+        {
+            return Err(NimbusError::InternalError(
+                "An experiment must always have an associated enrollment.",
+            ));
         }
+        // Write the changes to the Database.
+        write_changes(
+            writer,
+            enrollments_store,
+            &existing_enrollments,
+            &updated_enrollments,
+        )?;
+        write_changes(
+            writer,
+            experiments_store,
+            &existing_experiments,
+            &updated_experiments,
+        )?;
+        garbage_collect_experiment_opt_outs(
+            db,
+            writer,
+            &updated_experiments,
+            self.previous_enrollments_gc_time,
+        )?;
         Ok(enrollments_change_events)
     }
 
     /// Evolve and calculate the new set of enrollments, using the
     /// previous and current state of experiments and current enrollments.
+    ///
+    /// Experiments whose slug is in `opted_out_experiments` are treated as if the
+    /// user were not participating in experiments at all.
     pub(crate) fn evolve_enrollments(
         &self,
         is_user_participating: bool,
+        opted_out_experiments: &HashSet<String>,
         existing_experiments: &[Experiment],
         updated_experiments: &[Experiment],
         existing_enrollments: &[ExperimentEnrollment],
@@ -614,7 +939,7 @@ impl<'a> EnrollmentsEvolver<'a> {
         let mut updated_enrollments = Vec::with_capacity(all_slugs.len());
         for slug in all_slugs {
             let updated_enrollment = self.evolve_enrollment(
-                is_user_participating,
+                is_user_participating && !opted_out_experiments.contains(slug),
                 existing_experiments.get(slug).copied(),
                 updated_experiments.get(slug).copied(),
                 existing_enrollments.get(slug).copied(),
@@ -625,9 +950,73 @@ impl<'a> EnrollmentsEvolver<'a> {
             }
         }
 
+        if let Some(max_active_enrollments) = self.max_active_enrollments {
+            self.limit_active_enrollments(
+                max_active_enrollments,
+                &existing_enrollments,
+                &mut updated_enrollments,
+                &mut enrollment_events,
+            )?;
+        }
+
         Ok((updated_enrollments, enrollment_events))
     }
 
+    /// Ensure that at most `max_active_enrollments` of the updated enrollments are active.
+    ///
+    /// Enrollments which were already active before this update are always kept, so that
+    /// we never leave an experiment half-way through just because a new one came along.
+    /// New enrollments compete for the remaining slots in an order determined by hashing
+    /// the experiment slug with our `nimbus_id`, which is stable across updates for this
+    /// client but doesn't favour the same experiments for every client. The ones that
+    /// miss out become `NotEnrolled { TooManyExperiments }`, and will be evaluated again
+    /// on future updates in case a slot has become free.
+    fn limit_active_enrollments(
+        &self,
+        max_active_enrollments: usize,
+        existing_enrollments: &HashMap<String, &ExperimentEnrollment>,
+        updated_enrollments: &mut Vec<ExperimentEnrollment>,
+        out_enrollment_events: &mut Vec<EnrollmentChangeEvent>,
+    ) -> Result<()> {
+        let nimbus_id = self.nimbus_id.to_string();
+        let mut num_already_enrolled = 0;
+        let mut new_enrollments = Vec::new();
+        for (index, enrollment) in updated_enrollments.iter().enumerate() {
+            if !enrollment.status.is_enrolled() {
+                continue;
+            }
+            let was_enrolled = existing_enrollments
+                .get(&enrollment.slug)
+                .map_or(false, |e| e.status.is_enrolled());
+            if was_enrolled {
+                num_already_enrolled += 1;
+            } else {
+                let rank = sampling::truncated_hash(vec![&nimbus_id, &enrollment.slug])?;
+                new_enrollments.push((rank, index));
+            }
+        }
+
+        let available_slots = max_active_enrollments.saturating_sub(num_already_enrolled);
+        if new_enrollments.len() <= available_slots {
+            return Ok(());
+        }
+        new_enrollments.sort_unstable();
+        for (_, index) in new_enrollments.into_iter().skip(available_slots) {
+            let enrollment = &mut updated_enrollments[index];
+            log::debug!(
+                "Not enrolling in experiment '{}' (too many active experiments)",
+                &enrollment.slug
+            );
+            // The only event for a new enrollment is the `Enrollment` one, which
+            // no longer applies.
+            out_enrollment_events.retain(|event| event.experiment_slug != enrollment.slug);
+            enrollment.status = EnrollmentStatus::NotEnrolled {
+                reason: NotEnrolledReason::TooManyExperiments,
+            };
+        }
+        Ok(())
+    }
+
     /// Evolve a single enrollment using the previous and current state of an experiment.
     fn evolve_enrollment(
         &self,
@@ -653,17 +1042,20 @@ impl<'a> EnrollmentsEvolver<'a> {
                     enrollment.on_experiment_ended(out_enrollment_events)
                 }
                 // Known experiment.
-                (Some(_), Some(experiment), Some(enrollment)) => {
+                (Some(existing), Some(experiment), Some(enrollment)) => {
                     Some(enrollment.on_experiment_updated(
                         is_user_participating,
                         self.nimbus_id,
                         self.available_randomization_units,
                         self.app_context,
+                        existing,
                         experiment,
                         out_enrollment_events,
                     )?)
                 }
-                (None, None, Some(enrollment)) => enrollment.maybe_garbage_collect(),
+                (None, None, Some(enrollment)) => {
+                    enrollment.maybe_garbage_collect(self.previous_enrollments_gc_time)
+                }
                 (None, Some(_), Some(_)) => {
                     return Err(NimbusError::InternalError(
                         "New experiment but enrollment already exists.",
@@ -680,6 +1072,31 @@ impl<'a> EnrollmentsEvolver<'a> {
     }
 }
 
+/// Bring the items of `store` from `existing` to `updated`, both keyed by slug, by
+/// only putting the items which are new or have changed and deleting the ones
+/// which are gone.
+fn write_changes<T>(
+    writer: &mut Writer,
+    store: &SingleStore,
+    existing: &HashMap<String, &T>,
+    updated: &HashMap<String, &T>,
+) -> Result<()>
+where
+    T: PartialEq + serde::Serialize + for<'de> serde::Deserialize<'de>,
+{
+    for (slug, item) in updated {
+        if existing.get(slug) != Some(item) {
+            store.put(writer, slug, *item)?;
+        }
+    }
+    for slug in existing.keys() {
+        if !updated.contains_key(slug) {
+            store.delete(writer, slug)?;
+        }
+    }
+    Ok(())
+}
+
 fn map_experiments(experiments: &[Experiment]) -> HashMap<String, &Experiment> {
     let mut map_experiments = HashMap::with_capacity(experiments.len());
     for e in experiments {
@@ -696,6 +1113,7 @@ fn map_enrollments(enrollments: &[ExperimentEnrollment]) -> HashMap<String, &Exp
     map_enrollments
 }
 
+#[derive(Debug, Clone)]
 pub struct EnrollmentChangeEvent {
     pub experiment_slug: String,
     pub branch_slug: String,
@@ -722,7 +1140,7 @@ impl EnrollmentChangeEvent {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum EnrollmentChangeEventType {
     Enrollment,
     Disqualification,
@@ -743,6 +1161,8 @@ pub fn opt_in_with_branch(
     let enrollment = ExperimentEnrollment::from_explicit_opt_in(&exp, branch, &mut events)?;
     db.get_store(StoreId::Enrollments)
         .put(writer, experiment_slug, &enrollment)?;
+    // Opting back in overrides any earlier opt-out.
+    set_experiment_opt_out(db, writer, experiment_slug, false)?;
     Ok(events)
 }
 
@@ -758,9 +1178,102 @@ pub fn opt_out(
         .ok_or_else(|| NimbusError::NoSuchExperiment(experiment_slug.to_owned()))?;
     let updated_enrollment = existing_enrollment.on_explicit_opt_out(&mut events);
     enr_store.put(writer, experiment_slug, &updated_enrollment)?;
+    // Remember the opt-out beyond the lifetime of this enrollment record, so that
+    // we don't enroll again if an experiment with the same slug is relaunched.
+    set_experiment_opt_out(db, writer, experiment_slug, true)?;
     Ok(events)
 }
 
+/// The experiments the user has explicitly opted out of, keyed by slug, along with
+/// when each experiment was last seen to end, if it has since been removed.
+type ExperimentOptOuts = BTreeMap<String, Option<u64>>;
+
+fn read_experiment_opt_outs<'r>(
+    db: &Database,
+    reader: &'r impl Readable<'r>,
+) -> Result<ExperimentOptOuts> {
+    let meta_store = db.get_store(StoreId::Meta);
+    if let Some(opt_outs) = meta_store.get(reader, DB_KEY_EXPERIMENT_OPT_OUTS)? {
+        return Ok(opt_outs);
+    }
+    let legacy_opt_outs: Vec<String> = meta_store
+        .get(reader, DB_KEY_LEGACY_EXPERIMENT_OPT_OUTS)?
+        .unwrap_or_default();
+    Ok(legacy_opt_outs
+        .into_iter()
+        .map(|slug| (slug, None))
+        .collect())
+}
+
+/// Return the slugs of the experiments the user has explicitly opted out of.
+pub fn get_experiment_opt_outs<'r>(
+    db: &Database,
+    reader: &'r impl Readable<'r>,
+) -> Result<HashSet<String>> {
+    Ok(read_experiment_opt_outs(db, reader)?
+        .into_iter()
+        .map(|(slug, _)| slug)
+        .collect())
+}
+
+fn set_experiment_opt_out(
+    db: &Database,
+    writer: &mut Writer,
+    experiment_slug: &str,
+    opted_out: bool,
+) -> Result<()> {
+    let mut opt_outs = read_experiment_opt_outs(db, writer)?;
+    let changed = if opted_out {
+        opt_outs.insert(experiment_slug.to_owned(), None).is_none()
+    } else {
+        opt_outs.remove(experiment_slug).is_some()
+    };
+    // Avoid writing unless we have to.
+    if changed {
+        db.get_store(StoreId::Meta)
+            .put(writer, DB_KEY_EXPERIMENT_OPT_OUTS, &opt_outs)?;
+    }
+    Ok(())
+}
+
+/// Garbage collect the opt-outs of experiments which have been gone for longer than
+/// `gc_time`, the same way we do for their `WasEnrolled` enrollments.
+///
+/// We note when an opted-out experiment disappears from `experiments`, and forget
+/// that again if it comes back before `gc_time` has passed.
+fn garbage_collect_experiment_opt_outs(
+    db: &Database,
+    writer: &mut Writer,
+    experiments: &HashMap<String, &Experiment>,
+    gc_time: Duration,
+) -> Result<()> {
+    let opt_outs = read_experiment_opt_outs(db, writer)?;
+    let now = now_secs();
+    let updated_opt_outs: ExperimentOptOuts = opt_outs
+        .iter()
+        .filter_map(|(slug, experiment_ended_at)| {
+            let experiment_ended_at = match (experiments.contains_key(slug), experiment_ended_at) {
+                (true, _) => None,
+                (false, None) => Some(now),
+                (false, Some(ended_at)) => {
+                    if Duration::from_secs(now.saturating_sub(*ended_at)) >= gc_time {
+                        log::debug!("Garbage collecting opt-out of '{}'", slug);
+                        return None;
+                    }
+                    Some(*ended_at)
+                }
+            };
+            Some((slug.clone(), experiment_ended_at))
+        })
+        .collect();
+    // Avoid writing unless we have to.
+    if updated_opt_outs != opt_outs {
+        db.get_store(StoreId::Meta)
+            .put(writer, DB_KEY_EXPERIMENT_OPT_OUTS, &updated_opt_outs)?;
+    }
+    Ok(())
+}
+
 pub fn get_global_user_participation<'r>(
     db: &Database,
     reader: &'r impl Readable<'r>,
@@ -802,7 +1315,7 @@ pub fn reset_telemetry_identifiers(
     Ok(events)
 }
 
-fn now_secs() -> u64 {
+pub(crate) fn now_secs() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .expect("Current date before Unix Epoch.")
@@ -923,7 +1436,13 @@ mod tests {
         app_ctx: &'a AppContext,
         aru: &'a AvailableRandomizationUnits,
     ) -> EnrollmentsEvolver<'a> {
-        EnrollmentsEvolver::new(nimbus_id, aru, app_ctx)
+        EnrollmentsEvolver::new(
+            nimbus_id,
+            aru,
+            app_ctx,
+            None,
+            DEFAULT_PREVIOUS_ENROLLMENTS_GC_TIME,
+        )
     }
 
     #[test]
@@ -1210,6 +1729,57 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_evolver_experiment_update_error_then_experiment_fixed() -> Result<()> {
+        let exp = get_test_experiments()[0].clone();
+        let broken_exp = Experiment {
+            targeting: Some("This is not a valid JEXL expression".to_owned()),
+            ..exp.clone()
+        };
+        let (nimbus_id, app_ctx, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let mut events = vec![];
+        let enrollment = evolver
+            .evolve_enrollment(true, None, Some(&broken_exp), None, &mut events)?
+            .unwrap();
+        assert!(matches!(
+            enrollment.status,
+            EnrollmentStatus::Error {
+                reason: EnrollmentErrorReason::EvaluationError(_)
+            }
+        ));
+        assert!(events.is_empty());
+
+        // If the experiment doesn't change, we don't try again.
+        let unchanged = evolver
+            .evolve_enrollment(
+                true,
+                Some(&broken_exp),
+                Some(&broken_exp),
+                Some(&enrollment),
+                &mut events,
+            )?
+            .unwrap();
+        assert_eq!(unchanged, enrollment);
+        assert!(events.is_empty());
+
+        // Once the experiment is fixed, we evaluate it again and can enroll.
+        let fixed = evolver
+            .evolve_enrollment(
+                true,
+                Some(&broken_exp),
+                Some(&exp),
+                Some(&enrollment),
+                &mut events,
+            )?
+            .unwrap();
+        assert!(fixed.status.is_enrolled());
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].experiment_slug, exp.slug);
+        assert_eq!(events[0].change, EnrollmentChangeEventType::Enrollment);
+        Ok(())
+    }
+
     #[test]
     fn test_evolver_experiment_update_enrolled_then_targeting_changed() -> Result<()> {
         let exp = get_test_experiments()[0].clone();
@@ -1381,10 +1951,213 @@ mod tests {
         Ok(())
     }
 
+    fn opted_in_enrollment(exp: &Experiment, branch: &str) -> ExperimentEnrollment {
+        ExperimentEnrollment {
+            slug: exp.slug.clone(),
+            status: EnrollmentStatus::new_enrolled(
+                EnrolledReason::OptIn,
+                branch,
+                &exp.get_first_feature_id(),
+            ),
+        }
+    }
+
     #[test]
-    fn test_evolver_experiment_update_disqualified_then_opted_out() -> Result<()> {
+    fn test_evolver_experiment_update_opted_in_then_targeting_changed() -> Result<()> {
         let exp = get_test_experiments()[0].clone();
-        let (nimbus_id, app_ctx, aru) = local_ctx();
+        let (nimbus_id, mut app_ctx, aru) = local_ctx();
+        app_ctx.app_id = "foobar".to_owned(); // Make the experiment targeting fail.
+        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let mut events = vec![];
+        let existing_enrollment = opted_in_enrollment(&exp, "treatment");
+        let enrollment = evolver
+            .evolve_enrollment(
+                true,
+                Some(&exp),
+                Some(&exp),
+                Some(&existing_enrollment),
+                &mut events,
+            )?
+            .unwrap();
+        assert_eq!(enrollment, existing_enrollment);
+        assert!(events.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_evolver_experiment_update_opted_in_then_bucketing_changed() -> Result<()> {
+        let mut exp = get_test_experiments()[0].clone();
+        exp.bucket_config.count = 0; // Make the experiment bucketing fail.
+        let (nimbus_id, app_ctx, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let mut events = vec![];
+        let existing_enrollment = opted_in_enrollment(&exp, "treatment");
+        let enrollment = evolver
+            .evolve_enrollment(
+                true,
+                Some(&exp),
+                Some(&exp),
+                Some(&existing_enrollment),
+                &mut events,
+            )?
+            .unwrap();
+        assert_eq!(enrollment, existing_enrollment);
+        assert!(events.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_evolver_experiment_update_opted_in_then_randomization_unit_missing() -> Result<()> {
+        let mut exp = get_test_experiments()[0].clone();
+        exp.bucket_config.randomization_unit = crate::RandomizationUnit::ClientId;
+        let (nimbus_id, app_ctx, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let mut events = vec![];
+        let existing_enrollment = opted_in_enrollment(&exp, "treatment");
+        let enrollment = evolver
+            .evolve_enrollment(
+                true,
+                Some(&exp),
+                Some(&exp),
+                Some(&existing_enrollment),
+                &mut events,
+            )?
+            .unwrap();
+        assert_eq!(enrollment, existing_enrollment);
+        assert!(events.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_evolver_experiment_update_opted_in_then_experiment_paused() -> Result<()> {
+        let mut exp = get_test_experiments()[0].clone();
+        exp.is_enrollment_paused = true;
+        let (nimbus_id, app_ctx, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let mut events = vec![];
+        let existing_enrollment = opted_in_enrollment(&exp, "treatment");
+        let enrollment = evolver
+            .evolve_enrollment(
+                true,
+                Some(&exp),
+                Some(&exp),
+                Some(&existing_enrollment),
+                &mut events,
+            )?
+            .unwrap();
+        assert_eq!(enrollment, existing_enrollment);
+        assert!(events.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_evolver_experiment_update_opted_in_then_branch_disappears() -> Result<()> {
+        let mut exp = get_test_experiments()[0].clone();
+        let existing_enrollment = opted_in_enrollment(&exp, "treatment");
+        exp.branches.retain(|branch| branch.slug != "treatment");
+        let (nimbus_id, app_ctx, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let mut events = vec![];
+        let enrollment = evolver
+            .evolve_enrollment(
+                true,
+                Some(&exp),
+                Some(&exp),
+                Some(&existing_enrollment),
+                &mut events,
+            )?
+            .unwrap();
+        assert!(matches!(
+            enrollment.status,
+            EnrollmentStatus::Disqualified {
+                reason: DisqualifiedReason::Error,
+                ..
+            }
+        ));
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].change,
+            EnrollmentChangeEventType::Disqualification
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_evolver_experiment_update_opted_in_then_globally_opted_out() -> Result<()> {
+        let exp = get_test_experiments()[0].clone();
+        let (nimbus_id, app_ctx, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let mut events = vec![];
+        let existing_enrollment = opted_in_enrollment(&exp, "treatment");
+        let enrollment = evolver
+            .evolve_enrollment(
+                false,
+                Some(&exp),
+                Some(&exp),
+                Some(&existing_enrollment),
+                &mut events,
+            )?
+            .unwrap();
+        assert!(matches!(
+            enrollment.status,
+            EnrollmentStatus::Disqualified {
+                reason: DisqualifiedReason::OptOut,
+                ..
+            }
+        ));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].reason, Some("optout".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_evolver_experiment_ended_opted_in() -> Result<()> {
+        let exp = get_test_experiments()[0].clone();
+        let (nimbus_id, app_ctx, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let mut events = vec![];
+        let existing_enrollment = opted_in_enrollment(&exp, "treatment");
+        let enrollment = evolver
+            .evolve_enrollment(
+                true,
+                Some(&exp),
+                None,
+                Some(&existing_enrollment),
+                &mut events,
+            )?
+            .unwrap();
+        assert!(
+            matches!(enrollment.status, EnrollmentStatus::WasEnrolled { ref branch, .. } if branch == "treatment")
+        );
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].change, EnrollmentChangeEventType::Unenrollment);
+        Ok(())
+    }
+
+    #[test]
+    fn test_enrollment_opted_in_explicit_opt_out() {
+        let exp = get_test_experiments()[0].clone();
+        let mut events = vec![];
+        let existing_enrollment = opted_in_enrollment(&exp, "treatment");
+        let enrollment = existing_enrollment.on_explicit_opt_out(&mut events);
+        assert!(matches!(
+            enrollment.status,
+            EnrollmentStatus::Disqualified {
+                reason: DisqualifiedReason::OptOut,
+                ..
+            }
+        ));
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].change,
+            EnrollmentChangeEventType::Disqualification
+        );
+    }
+
+    #[test]
+    fn test_evolver_experiment_update_disqualified_then_opted_out() -> Result<()> {
+        let exp = get_test_experiments()[0].clone();
+        let (nimbus_id, app_ctx, aru) = local_ctx();
         let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
         let mut events = vec![];
         let enrollment_id = Uuid::new_v4();
@@ -1483,7 +2256,7 @@ mod tests {
         let existing_enrollment = ExperimentEnrollment {
             slug: exp.slug.clone(),
             status: EnrollmentStatus::Error {
-                reason: "heh".to_owned(),
+                reason: EnrollmentErrorReason::Unknown("heh".to_owned()),
             },
         };
         let enrollment = evolver
@@ -1641,7 +2414,9 @@ mod tests {
             status: EnrollmentStatus::WasEnrolled {
                 enrollment_id: Uuid::new_v4(),
                 branch: "control".to_owned(),
-                experiment_ended_at: now_secs() - PREVIOUS_ENROLLMENTS_GC_TIME.as_secs() - 60,
+                experiment_ended_at: now_secs()
+                    - DEFAULT_PREVIOUS_ENROLLMENTS_GC_TIME.as_secs()
+                    - 60,
             },
         };
         let enrollment =
@@ -1651,6 +2426,39 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_evolver_garbage_collection_custom_threshold() -> Result<()> {
+        let (nimbus_id, app_ctx, aru) = local_ctx();
+        let evolver =
+            EnrollmentsEvolver::new(&nimbus_id, &aru, &app_ctx, None, Duration::from_secs(3600));
+        let mut events = vec![];
+        let recent_enrollment = ExperimentEnrollment {
+            slug: "secure-gold".to_owned(),
+            status: EnrollmentStatus::WasEnrolled {
+                enrollment_id: Uuid::new_v4(),
+                branch: "control".to_owned(),
+                experiment_ended_at: now_secs() - 60,
+            },
+        };
+        let enrollment =
+            evolver.evolve_enrollment(true, None, None, Some(&recent_enrollment), &mut events)?;
+        assert_eq!(enrollment, Some(recent_enrollment));
+
+        let old_enrollment = ExperimentEnrollment {
+            slug: "secure-gold".to_owned(),
+            status: EnrollmentStatus::WasEnrolled {
+                enrollment_id: Uuid::new_v4(),
+                branch: "control".to_owned(),
+                experiment_ended_at: now_secs() - 3600 - 60,
+            },
+        };
+        let enrollment =
+            evolver.evolve_enrollment(true, None, None, Some(&old_enrollment), &mut events)?;
+        assert!(enrollment.is_none());
+        assert!(events.is_empty());
+        Ok(())
+    }
+
     #[test]
     fn test_evolver_new_experiment_enrollment_already_exists() {
         let exp = get_test_experiments()[0].clone();
@@ -1693,6 +2501,87 @@ mod tests {
             .unwrap();
     }
 
+    #[test]
+    fn test_evolver_max_active_enrollments() -> Result<()> {
+        let exps = get_test_experiments();
+        let (nimbus_id, app_ctx, aru) = local_ctx();
+        let evolver = EnrollmentsEvolver::new(
+            &nimbus_id,
+            &aru,
+            &app_ctx,
+            Some(1),
+            DEFAULT_PREVIOUS_ENROLLMENTS_GC_TIME,
+        );
+        // Both experiments enroll everyone, but we only have room for one.
+        let (enrollments, events) =
+            evolver.evolve_enrollments(true, &HashSet::new(), &[], &exps, &[])?;
+        assert_eq!(enrollments.len(), 2);
+        let enrolled: Vec<_> = enrollments
+            .iter()
+            .filter(|e| e.status.is_enrolled())
+            .collect();
+        assert_eq!(enrolled.len(), 1);
+        let enrolled_slug = enrolled[0].slug.clone();
+        assert!(enrollments.iter().any(|e| matches!(
+            e.status,
+            EnrollmentStatus::NotEnrolled {
+                reason: NotEnrolledReason::TooManyExperiments
+            }
+        )));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].experiment_slug, enrolled_slug);
+
+        // The choice is deterministic, so the same experiment wins again from scratch.
+        let (enrollments_again, _) =
+            evolver.evolve_enrollments(true, &HashSet::new(), &[], &exps, &[])?;
+        assert!(enrollments_again
+            .iter()
+            .any(|e| e.slug == enrolled_slug && e.status.is_enrolled()));
+
+        // Updating with the limit still in place changes nothing.
+        let (enrollments, events) =
+            evolver.evolve_enrollments(true, &HashSet::new(), &exps, &exps, &enrollments)?;
+        assert_eq!(
+            enrollments
+                .iter()
+                .filter(|e| e.status.is_enrolled())
+                .count(),
+            1
+        );
+        assert!(events.is_empty());
+
+        // Existing enrollments are kept even if the limit is lowered.
+        let evolver = EnrollmentsEvolver::new(
+            &nimbus_id,
+            &aru,
+            &app_ctx,
+            Some(0),
+            DEFAULT_PREVIOUS_ENROLLMENTS_GC_TIME,
+        );
+        let (enrollments, events) =
+            evolver.evolve_enrollments(true, &HashSet::new(), &exps, &exps, &enrollments)?;
+        assert!(enrollments
+            .iter()
+            .any(|e| e.slug == enrolled_slug && e.status.is_enrolled()));
+        assert!(events.is_empty());
+
+        // Once the limit is raised, the other experiment gets its turn.
+        let evolver = EnrollmentsEvolver::new(
+            &nimbus_id,
+            &aru,
+            &app_ctx,
+            Some(2),
+            DEFAULT_PREVIOUS_ENROLLMENTS_GC_TIME,
+        );
+        let (enrollments, events) =
+            evolver.evolve_enrollments(true, &HashSet::new(), &exps, &exps, &enrollments)?;
+        assert!(enrollments.iter().all(|e| e.status.is_enrolled()));
+        assert_eq!(events.len(), 1);
+        assert_ne!(events[0].experiment_slug, enrolled_slug);
+        assert_eq!(events[0].change, EnrollmentChangeEventType::Enrollment);
+        Ok(())
+    }
+
     #[test]
     fn test_enrollment_explicit_opt_in() -> Result<()> {
         let exp = get_test_experiments()[0].clone();
@@ -1812,7 +2701,13 @@ mod tests {
         };
         assert_eq!(get_enrollments(&db, &writer)?.len(), 0);
 
-        let evolver = EnrollmentsEvolver::new(&nimbus_id, &aru, &app_ctx);
+        let evolver = EnrollmentsEvolver::new(
+            &nimbus_id,
+            &aru,
+            &app_ctx,
+            None,
+            DEFAULT_PREVIOUS_ENROLLMENTS_GC_TIME,
+        );
         let events = evolver.evolve_enrollments_in_db(&db, &mut writer, &[exp1])?;
 
         let enrollments = get_enrollments(&db, &writer)?;
@@ -1893,7 +2788,13 @@ mod tests {
         assert_eq!(get_enrollments(&db, &writer)?.len(), 0);
         let exps = get_test_experiments();
 
-        let evolver = EnrollmentsEvolver::new(&nimbus_id, &aru, &app_ctx);
+        let evolver = EnrollmentsEvolver::new(
+            &nimbus_id,
+            &aru,
+            &app_ctx,
+            None,
+            DEFAULT_PREVIOUS_ENROLLMENTS_GC_TIME,
+        );
         let events = evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
 
         let enrollments = get_enrollments(&db, &writer)?;
@@ -1902,7 +2803,13 @@ mod tests {
 
         // pretend we just updated from the server and one of the 2 is missing.
         let exps = &[exps[1].clone()];
-        let evolver = EnrollmentsEvolver::new(&nimbus_id, &aru, &app_ctx);
+        let evolver = EnrollmentsEvolver::new(
+            &nimbus_id,
+            &aru,
+            &app_ctx,
+            None,
+            DEFAULT_PREVIOUS_ENROLLMENTS_GC_TIME,
+        );
         let events = evolver.evolve_enrollments_in_db(&db, &mut writer, exps)?;
 
         // should only have 1 now.
@@ -1941,7 +2848,13 @@ mod tests {
         // User has opted out of new experiments.
         set_global_user_participation(&db, &mut writer, false)?;
 
-        let evolver = EnrollmentsEvolver::new(&nimbus_id, &aru, &app_ctx);
+        let evolver = EnrollmentsEvolver::new(
+            &nimbus_id,
+            &aru,
+            &app_ctx,
+            None,
+            DEFAULT_PREVIOUS_ENROLLMENTS_GC_TIME,
+        );
         let events = evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
 
         let enrollments = get_enrollments(&db, &writer)?;
@@ -1966,7 +2879,13 @@ mod tests {
         // User opts in, and updating should enroll us in 2 experiments.
         set_global_user_participation(&db, &mut writer, true)?;
 
-        let evolver = EnrollmentsEvolver::new(&nimbus_id, &aru, &app_ctx);
+        let evolver = EnrollmentsEvolver::new(
+            &nimbus_id,
+            &aru,
+            &app_ctx,
+            None,
+            DEFAULT_PREVIOUS_ENROLLMENTS_GC_TIME,
+        );
         let events = evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
 
         let enrollments = get_enrollments(&db, &writer)?;
@@ -1984,7 +2903,13 @@ mod tests {
         // Opting out and updating should give us two disqualified enrollments
         set_global_user_participation(&db, &mut writer, false)?;
 
-        let evolver = EnrollmentsEvolver::new(&nimbus_id, &aru, &app_ctx);
+        let evolver = EnrollmentsEvolver::new(
+            &nimbus_id,
+            &aru,
+            &app_ctx,
+            None,
+            DEFAULT_PREVIOUS_ENROLLMENTS_GC_TIME,
+        );
         let events = evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
 
         let enrollments = get_enrollments(&db, &writer)?;
@@ -2010,7 +2935,13 @@ mod tests {
         // Opting in again and updating SHOULD NOT enroll us again (we've been disqualified).
         set_global_user_participation(&db, &mut writer, true)?;
 
-        let evolver = EnrollmentsEvolver::new(&nimbus_id, &aru, &app_ctx);
+        let evolver = EnrollmentsEvolver::new(
+            &nimbus_id,
+            &aru,
+            &app_ctx,
+            None,
+            DEFAULT_PREVIOUS_ENROLLMENTS_GC_TIME,
+        );
         let events = evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
 
         let enrollments = get_enrollments(&db, &writer)?;
@@ -2035,6 +2966,298 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_opt_out_survives_experiment_restart() -> Result<()> {
+        let _ = env_logger::try_init();
+        let tmp_dir = TempDir::new("test_opt_out_survives_experiment_restart")?;
+        let db = Database::new(&tmp_dir)?;
+        let mut writer = db.write()?;
+        let (nimbus_id, app_ctx, aru) = local_ctx();
+        let exps = get_test_experiments();
+        let evolver = EnrollmentsEvolver::new(
+            &nimbus_id,
+            &aru,
+            &app_ctx,
+            None,
+            DEFAULT_PREVIOUS_ENROLLMENTS_GC_TIME,
+        );
+
+        evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
+        assert_eq!(get_enrollments(&db, &writer)?.len(), 2);
+
+        opt_out(&db, &mut writer, "secure-gold")?;
+        assert!(get_experiment_opt_outs(&db, &writer)?.contains("secure-gold"));
+
+        // The experiment ends, and its enrollment record is eventually garbage collected.
+        evolver.evolve_enrollments_in_db(&db, &mut writer, &exps[1..])?;
+        db.get_store(StoreId::Enrollments)
+            .delete(&mut writer, "secure-gold")?;
+
+        // When the experiment is relaunched, we should not enroll again.
+        let events = evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
+        assert!(events.is_empty());
+        let enrollments = get_enrollments(&db, &writer)?;
+        assert_eq!(enrollments.len(), 1);
+        assert_eq!(enrollments[0].slug, "secure-silver");
+        let ee: ExperimentEnrollment = db
+            .get_store(StoreId::Enrollments)
+            .get(&writer, "secure-gold")?
+            .expect("should exist");
+        assert!(matches!(
+            ee.status,
+            EnrollmentStatus::NotEnrolled {
+                reason: NotEnrolledReason::OptOut
+            }
+        ));
+
+        // Explicitly opting back in clears the opt-out.
+        opt_in_with_branch(&db, &mut writer, "secure-gold", "treatment")?;
+        assert!(get_experiment_opt_outs(&db, &writer)?.is_empty());
+        let events = evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
+        assert!(events.is_empty());
+        assert_eq!(get_enrollments(&db, &writer)?.len(), 2);
+
+        writer.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_opt_outs_are_garbage_collected() -> Result<()> {
+        let _ = env_logger::try_init();
+        let tmp_dir = TempDir::new("test_opt_outs_are_garbage_collected")?;
+        let db = Database::new(&tmp_dir)?;
+        let mut writer = db.write()?;
+        let (nimbus_id, app_ctx, aru) = local_ctx();
+        let exps = get_test_experiments();
+        let evolver =
+            EnrollmentsEvolver::new(&nimbus_id, &aru, &app_ctx, None, Duration::from_secs(0));
+
+        evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
+        opt_out(&db, &mut writer, "secure-gold")?;
+        let opt_outs = read_experiment_opt_outs(&db, &writer)?;
+        assert_eq!(opt_outs.get("secure-gold"), Some(&None));
+
+        // While the experiment is live, the opt-out is kept.
+        evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
+        assert!(get_experiment_opt_outs(&db, &writer)?.contains("secure-gold"));
+
+        // When the experiment ends, we note when it did...
+        evolver.evolve_enrollments_in_db(&db, &mut writer, &exps[1..])?;
+        let opt_outs = read_experiment_opt_outs(&db, &writer)?;
+        assert!(
+            matches!(opt_outs.get("secure-gold"), Some(Some(ended_at)) if *ended_at <= now_secs())
+        );
+
+        // ...and forget about it if the experiment is relaunched in time.
+        db.get_store(StoreId::Enrollments)
+            .delete(&mut writer, "secure-gold")?;
+        let events = evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
+        assert!(events.is_empty());
+        let opt_outs = read_experiment_opt_outs(&db, &writer)?;
+        assert_eq!(opt_outs.get("secure-gold"), Some(&None));
+
+        // Otherwise, the opt-out is garbage collected once `gc_time` has passed.
+        evolver.evolve_enrollments_in_db(&db, &mut writer, &exps[1..])?;
+        assert!(get_experiment_opt_outs(&db, &writer)?.contains("secure-gold"));
+        evolver.evolve_enrollments_in_db(&db, &mut writer, &exps[1..])?;
+        assert!(get_experiment_opt_outs(&db, &writer)?.is_empty());
+
+        // So relaunching the experiment after that enrolls us again.
+        let events = evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].experiment_slug, "secure-gold");
+        assert_eq!(events[0].change, EnrollmentChangeEventType::Enrollment);
+
+        writer.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_legacy_opt_outs() -> Result<()> {
+        let _ = env_logger::try_init();
+        let tmp_dir = TempDir::new("test_legacy_opt_outs")?;
+        let db = Database::new(&tmp_dir)?;
+        let mut writer = db.write()?;
+        let meta_store = db.get_store(StoreId::Meta);
+        meta_store.put(
+            &mut writer,
+            DB_KEY_LEGACY_EXPERIMENT_OPT_OUTS,
+            &vec!["secure-gold".to_string()],
+        )?;
+
+        // Opt-outs kept by older versions are still honored...
+        let opt_outs = read_experiment_opt_outs(&db, &writer)?;
+        assert_eq!(opt_outs.get("secure-gold"), Some(&None));
+
+        // ...and left as they were when they're changed, for those versions.
+        set_experiment_opt_out(&db, &mut writer, "startup-gold", true)?;
+        assert_eq!(get_experiment_opt_outs(&db, &writer)?.len(), 2);
+        assert_eq!(
+            meta_store.get::<Vec<String>, _>(&writer, DB_KEY_LEGACY_EXPERIMENT_OPT_OUTS)?,
+            Some(vec!["secure-gold".to_string()])
+        );
+        writer.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_previous_enrollments() -> Result<()> {
+        let _ = env_logger::try_init();
+        let tmp_dir = TempDir::new("test_get_previous_enrollments")?;
+        let db = Database::new(&tmp_dir)?;
+        let mut writer = db.write()?;
+        let (nimbus_id, app_ctx, aru) = local_ctx();
+        let exps = get_test_experiments();
+        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
+        assert!(get_previous_enrollments(&db, &writer)?.is_empty());
+        let enrolled = get_enrollments(&db, &writer)?;
+        let gold = enrolled.iter().find(|e| e.slug == "secure-gold").unwrap();
+
+        // "secure-gold" ends.
+        evolver.evolve_enrollments_in_db(&db, &mut writer, &exps[1..])?;
+        let previous = get_previous_enrollments(&db, &writer)?;
+        assert_eq!(previous.len(), 1);
+        assert_eq!(previous[0].slug, "secure-gold");
+        assert_eq!(previous[0].branch_slug, gold.branch_slug);
+        assert_eq!(previous[0].enrollment_id, gold.enrollment_id);
+        assert!(previous[0].experiment_ended_at <= now_secs());
+
+        writer.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_evolve_enrollments_in_db_only_writes_changes() -> Result<()> {
+        let _ = env_logger::try_init();
+        let tmp_dir = TempDir::new("test_evolve_enrollments_in_db_only_writes_changes")?;
+        let db = Database::new(&tmp_dir)?;
+        let mut writer = db.write()?;
+        let (nimbus_id, app_ctx, aru) = local_ctx();
+        let mut exps = get_test_experiments();
+        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
+
+        // Tag every stored record with a field we don't know about, which we'll only
+        // lose if the record is rewritten.
+        let experiments_store = db.get_store(StoreId::Experiments);
+        let enrollments_store = db.get_store(StoreId::Enrollments);
+        for store in &[experiments_store, enrollments_store] {
+            for (slug, mut value) in store.collect_all_with_keys::<serde_json::Value, _>(&writer)? {
+                value["marker"] = json!(true);
+                store.put(&mut writer, &slug, &value)?;
+            }
+        }
+        let is_marked = |store: &SingleStore, writer: &Writer, slug: &str| -> Result<bool> {
+            let value: serde_json::Value = store.get(writer, slug)?.expect("should exist");
+            Ok(value.get("marker").is_some())
+        };
+
+        // Nothing changed, so nothing is written.
+        let events = evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
+        assert!(events.is_empty());
+        for slug in &["secure-gold", "secure-silver"] {
+            assert!(is_marked(experiments_store, &writer, slug)?);
+            assert!(is_marked(enrollments_store, &writer, slug)?);
+        }
+
+        // Only the experiment which changed is rewritten.
+        exps[1].user_facing_name = "A new name".to_owned();
+        evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
+        assert!(is_marked(experiments_store, &writer, "secure-gold")?);
+        assert!(!is_marked(experiments_store, &writer, "secure-silver")?);
+        assert!(is_marked(enrollments_store, &writer, "secure-silver")?);
+
+        // And the experiment which was removed is deleted.
+        evolver.evolve_enrollments_in_db(&db, &mut writer, &exps[1..])?;
+        let experiments: Vec<Experiment> = experiments_store.collect_all(&writer)?;
+        assert_eq!(experiments.len(), 1);
+        assert_eq!(experiments[0].slug, "secure-silver");
+        // While its enrollment is updated, to record that it has ended.
+        assert!(!is_marked(enrollments_store, &writer, "secure-gold")?);
+
+        writer.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_enrollment_statuses() -> Result<()> {
+        let _ = env_logger::try_init();
+        let tmp_dir = TempDir::new("test_get_enrollment_statuses")?;
+        let db = Database::new(&tmp_dir)?;
+        let mut writer = db.write()?;
+        let (nimbus_id, app_ctx, aru) = local_ctx();
+        let exps = get_test_experiments();
+        let evolver = EnrollmentsEvolver::new(
+            &nimbus_id,
+            &aru,
+            &app_ctx,
+            None,
+            DEFAULT_PREVIOUS_ENROLLMENTS_GC_TIME,
+        );
+        evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
+        opt_out(&db, &mut writer, "secure-gold")?;
+        let store = db.get_store(StoreId::Enrollments);
+        store.put(
+            &mut writer,
+            "not-enrolled",
+            &ExperimentEnrollment {
+                slug: "not-enrolled".to_owned(),
+                status: EnrollmentStatus::NotEnrolled {
+                    reason: NotEnrolledReason::EnrollmentsPaused,
+                },
+            },
+        )?;
+        store.put(
+            &mut writer,
+            "errored",
+            &ExperimentEnrollment {
+                slug: "errored".to_owned(),
+                status: EnrollmentStatus::Error {
+                    reason: EnrollmentErrorReason::MissingRandomizationUnit,
+                },
+            },
+        )?;
+
+        let mut statuses = get_enrollment_statuses(&db, &writer)?;
+        statuses.sort_by(|a, b| a.slug.cmp(&b.slug));
+        assert_eq!(statuses.len(), 4);
+
+        assert_eq!(statuses[0].slug, "errored");
+        assert_eq!(statuses[0].status, EnrollmentStatusType::Error);
+        assert_eq!(
+            statuses[0].reason,
+            Some(EnrollmentStatusReason::MissingRandomizationUnit)
+        );
+        assert_eq!(statuses[0].branch_slug, None);
+
+        assert_eq!(statuses[1].slug, "not-enrolled");
+        assert_eq!(statuses[1].status, EnrollmentStatusType::NotEnrolled);
+        assert_eq!(
+            statuses[1].reason,
+            Some(EnrollmentStatusReason::EnrollmentsPaused)
+        );
+        assert_eq!(statuses[1].enrollment_id, None);
+
+        assert_eq!(statuses[2].slug, "secure-gold");
+        assert_eq!(statuses[2].status, EnrollmentStatusType::Disqualified);
+        assert_eq!(statuses[2].reason, Some(EnrollmentStatusReason::OptOut));
+        assert!(statuses[2].branch_slug.is_some());
+        assert!(statuses[2].enrollment_id.is_some());
+
+        assert_eq!(statuses[3].slug, "secure-silver");
+        assert_eq!(statuses[3].status, EnrollmentStatusType::Enrolled);
+        assert_eq!(statuses[3].reason, Some(EnrollmentStatusReason::Qualified));
+        let enrolled = get_enrollments(&db, &writer)?;
+        assert_eq!(
+            statuses[3].enrollment_id.as_ref(),
+            Some(&enrolled[0].enrollment_id)
+        );
+
+        writer.commit()?;
+        Ok(())
+    }
+
     #[test]
     fn test_telemetry_reset() -> Result<()> {
         let _ = env_logger::try_init();
@@ -2198,4 +3421,66 @@ mod test_schema_bw_compat {
             matches!(enroll.status, EnrollmentStatus::Enrolled{ ref feature_id, ..} if feature_id == "some_control")
         );
     }
+
+    // Before DB version 2, the `Error` status stored a stringified `NimbusError`.
+    // These are the strings it could contain, which the v1 -> v2 migration maps
+    // to an `EnrollmentErrorReason`.
+    #[test]
+    fn test_experiment_enrollment_schema_with_string_error_reasons() {
+        // ⚠️ Warning : Do not change the JSON data used by this test. ⚠️
+        let legacy: serde_json::Value = json!([
+            {"slug": "a", "status": {"Error": {"reason": "No randomization unit"}}},
+            {"slug": "b", "status": {"Error": {"reason": "Invalid Expression - didn't evaluate to a bool"}}},
+            {"slug": "c", "status": {"Error": {"reason": "EvaluationError: Unexpected token"}}},
+            {"slug": "d", "status": {"Error": {"reason": "Something else"}}},
+        ]);
+        let reasons: Vec<EnrollmentErrorReason> = legacy
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| {
+                EnrollmentErrorReason::from_legacy_reason(
+                    e.pointer("/status/Error/reason").unwrap().as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                EnrollmentErrorReason::MissingRandomizationUnit,
+                EnrollmentErrorReason::InvalidTargeting,
+                EnrollmentErrorReason::EvaluationError("Unexpected token".to_owned()),
+                EnrollmentErrorReason::Unknown("Something else".to_owned()),
+            ]
+        );
+    }
+
+    // In DB version 2 the `Error` status started storing an `EnrollmentErrorReason`.
+    // This tests the data as it was after that change.
+    #[test]
+    fn test_experiment_enrollment_schema_with_structured_error_reasons() {
+        // ⚠️ Warning : Do not change the JSON data used by this test. ⚠️
+        let enroll: ExperimentEnrollment = serde_json::from_value(json!({
+            "slug": "secure-gold",
+            "status": {"Error": {"reason": "MissingRandomizationUnit"}}
+        }))
+        .unwrap();
+        assert_eq!(
+            enroll.status,
+            EnrollmentStatus::Error {
+                reason: EnrollmentErrorReason::MissingRandomizationUnit
+            }
+        );
+        let enroll: ExperimentEnrollment = serde_json::from_value(json!({
+            "slug": "secure-gold",
+            "status": {"Error": {"reason": {"EvaluationError": "Unexpected token"}}}
+        }))
+        .unwrap();
+        assert_eq!(
+            enroll.status,
+            EnrollmentStatus::Error {
+                reason: EnrollmentErrorReason::EvaluationError("Unexpected token".to_owned())
+            }
+        );
+    }
 }
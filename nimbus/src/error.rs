@@ -37,10 +37,18 @@ pub enum NimbusError {
     UuidError(#[from] uuid::Error),
     #[error("Error in network response: {0}")]
     ResponseError(String),
+    #[error("Client error {0} in network response: {1}")]
+    ClientResponseError(u16, String),
+    #[error("Server error {0} in network response: {1}")]
+    ServerResponseError(u16, String),
     #[error("Invalid experiments response received")]
     InvalidExperimentFormat,
+    #[error("The collection of experiments is too large: {0}")]
+    CollectionTooLarge(String),
     #[error("Invalid path: {0}")]
     InvalidPath(String),
+    #[error("Invalid Remote Settings config: {0}")]
+    InvalidConfig(String),
     #[error("Internal error: {0}")]
     InternalError(&'static str),
     #[error("The experiment {0} does not exist")]
@@ -51,6 +59,12 @@ pub enum NimbusError {
     BackoffError(u64),
     #[error("Initialization of the database is not yet complete")]
     DatabaseNotReady,
+    #[error("The database is already in use by another client")]
+    DatabaseInUse,
+    #[error("Invalid exported state: {0}")]
+    InvalidExportedState(String),
+    #[error("Invalid content signature: {0}")]
+    SignatureError(String),
 }
 
 impl<'a> From<jexl_eval::error::EvaluationError<'a>> for NimbusError {
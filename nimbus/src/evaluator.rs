@@ -4,7 +4,8 @@
  */
 
 use crate::enrollment::{
-    EnrolledReason, EnrollmentStatus, ExperimentEnrollment, NotEnrolledReason,
+    EnrolledReason, EnrollmentErrorReason, EnrollmentStatus, ExperimentEnrollment,
+    NotEnrolledReason,
 };
 use crate::{
     error::{NimbusError, Result},
@@ -35,12 +36,11 @@ impl Bucket {
 ///
 /// An `ExperimentEnrollment` -  you need to inspect the EnrollmentStatus to
 /// determine if the user is actually enrolled.
-/// # Errors:
-///
-/// The function can return errors in one of the following cases (but not limited to):
 ///
-/// - If the bucket sampling failed (i.e we could not find if the user should or should not be enrolled in the experiment based on the bucketing)
-/// - If an error occurs while determining the branch the user should be enrolled in any of the experiments
+/// If the bucket sampling fails (i.e we could not find if the user should or should not
+/// be enrolled in the experiment based on the bucketing), or an error occurs while
+/// determining the branch the user should be enrolled in, the returned enrollment has
+/// an `EnrollmentStatus::Error` status with `EnrollmentErrorReason::SamplingFailure`.
 pub fn evaluate_enrollment(
     nimbus_id: &Uuid,
     available_randomization_units: &AvailableRandomizationUnits,
@@ -110,24 +110,34 @@ pub fn evaluate_enrollment(
             match available_randomization_units
                 .get_value(&nimbus_id.to_string(), &bucket_config.randomization_unit)
             {
-                Some(id) => {
-                    if sampling::bucket_sample(
-                        vec![id.to_owned(), bucket_config.namespace],
-                        bucket_config.start,
-                        bucket_config.count,
-                        bucket_config.total,
-                    )? {
-                        EnrollmentStatus::new_enrolled(
-                            EnrolledReason::Qualified,
-                            &choose_branch(&exp.slug, &exp.branches, &id)?.clone().slug,
-                            &exp.get_first_feature_id(),
-                        )
+                Some(id) => match sampling::bucket_sample(
+                    vec![id.to_owned(), bucket_config.namespace],
+                    bucket_config.start,
+                    bucket_config.count,
+                    bucket_config.total,
+                )
+                .and_then(|selected| {
+                    Ok(if selected {
+                        Some(choose_branch(&exp.slug, &exp.branches, &id)?)
                     } else {
-                        EnrollmentStatus::NotEnrolled {
-                            reason: NotEnrolledReason::NotSelected,
+                        None
+                    })
+                }) {
+                    Ok(Some(branch)) => EnrollmentStatus::new_enrolled(
+                        EnrolledReason::Qualified,
+                        &branch.slug,
+                        &exp.get_first_feature_id(),
+                    ),
+                    Ok(None) => EnrollmentStatus::NotEnrolled {
+                        reason: NotEnrolledReason::NotSelected,
+                    },
+                    Err(e) => {
+                        log::warn!("Sampling failed for experiment {}: {}", &exp.slug, e);
+                        EnrollmentStatus::Error {
+                            reason: EnrollmentErrorReason::SamplingFailure(e.to_string()),
                         }
                     }
-                }
+                },
                 None => {
                     // XXX: When we link in glean, it would be nice if we could emit
                     // a failure telemetry event here.
@@ -136,7 +146,7 @@ pub fn evaluate_enrollment(
                         &exp.slug
                     );
                     EnrollmentStatus::Error {
-                        reason: "No randomization unit".into(),
+                        reason: EnrollmentErrorReason::MissingRandomizationUnit,
                     }
                 }
             }
@@ -202,11 +212,11 @@ fn targeting(expression_statement: &str, ctx: &AppContext) -> Option<EnrollmentS
                 reason: NotEnrolledReason::NotTargeted,
             }),
             None => Some(EnrollmentStatus::Error {
-                reason: NimbusError::InvalidExpression.to_string(),
+                reason: EnrollmentErrorReason::InvalidTargeting,
             }),
         },
         Err(e) => Some(EnrollmentStatus::Error {
-            reason: NimbusError::EvaluationError(e.to_string()).to_string(),
+            reason: EnrollmentErrorReason::EvaluationError(e.to_string()),
         }),
     }
 }
@@ -313,7 +323,7 @@ mod tests {
         assert_eq!(
             targeting(expression_statement, &Default::default()),
             Some(EnrollmentStatus::Error {
-                reason: "Invalid Expression - didn't evaluate to a bool".to_string()
+                reason: EnrollmentErrorReason::InvalidTargeting
             })
         )
     }
@@ -323,9 +333,12 @@ mod tests {
         // This is an invalid JEXL statement
         let expression_statement = "This is not a valid JEXL expression";
 
-        assert!(
-            matches!(targeting(expression_statement, &Default::default()), Some(EnrollmentStatus::Error { reason }) if reason.starts_with("EvaluationError:"))
-        )
+        assert!(matches!(
+            targeting(expression_statement, &Default::default()),
+            Some(EnrollmentStatus::Error {
+                reason: EnrollmentErrorReason::EvaluationError(_)
+            })
+        ))
     }
 
     #[test]
@@ -412,6 +425,38 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_sampling_failure() {
+        // An experiment with no branches to choose from.
+        let experiment = Experiment {
+            schema_version: "1.0.0".to_string(),
+            slug: "TEST_EXP".to_string(),
+            bucket_config: BucketConfig {
+                randomization_unit: RandomizationUnit::NimbusId,
+                start: 0,
+                count: 10000,
+                total: 10000,
+                ..Default::default()
+            },
+            branches: vec![],
+            ..Default::default()
+        };
+
+        let enrollment = evaluate_enrollment(
+            &uuid::Uuid::new_v4(),
+            &Default::default(),
+            &Default::default(),
+            &experiment,
+        )
+        .unwrap();
+        assert!(matches!(
+            enrollment.status,
+            EnrollmentStatus::Error {
+                reason: EnrollmentErrorReason::SamplingFailure(_)
+            }
+        ));
+    }
+
     #[test]
     fn test_wrong_randomization_units() {
         let experiment = Experiment {
@@ -465,7 +510,12 @@ mod tests {
         )
         .unwrap();
         // The status should be `Error`
-        assert!(matches!(enrollment.status, EnrollmentStatus::Error { .. }));
+        assert!(matches!(
+            enrollment.status,
+            EnrollmentStatus::Error {
+                reason: EnrollmentErrorReason::MissingRandomizationUnit
+            }
+        ));
 
         // Fits because of the client_id.
         let available_randomization_units = AvailableRandomizationUnits::with_client_id("bobo");
@@ -0,0 +1,303 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Exporting the complete persisted state of a client as a JSON document, for
+//! bug reports, and importing it again to reproduce issues on another device.
+//!
+//! The document has one object per store, mapping each key to its JSON value:
+//!
+//! ```json
+//! {"meta": {...}, "experiments": {...}, "enrollments": {...}, "updates": {...}}
+//! ```
+//!
+//! Only the `Meta` and `Updates` keys which describe the user's choices and the
+//! experiments are exported: identifiers which could be used to track the user,
+//! and the client's own bookkeeping (eg, its sync and backoff state), are left out.
+
+use crate::enrollment::{
+    ExperimentEnrollment, DB_KEY_EXPERIMENT_OPT_OUTS, DB_KEY_GLOBAL_USER_PARTICIPATION,
+    DB_KEY_LEGACY_EXPERIMENT_OPT_OUTS,
+};
+use crate::error::{NimbusError, Result};
+use crate::persistence::{
+    Database, Readable, SingleStore, StoreId, Writer, DB_KEY_DB_VERSION, DB_VERSION,
+};
+use crate::updating::KEY_PENDING_UPDATES;
+use crate::Experiment;
+use serde_derive::*;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+// The `Meta` and `Updates` keys which are exported, and replaced on import. The
+// values of all the other keys are internal to a client, so we keep our current
+// ones when importing.
+const EXPORTED_META_KEYS: &[&str] = &[
+    DB_KEY_DB_VERSION,
+    DB_KEY_GLOBAL_USER_PARTICIPATION,
+    DB_KEY_EXPERIMENT_OPT_OUTS,
+    DB_KEY_LEGACY_EXPERIMENT_OPT_OUTS,
+];
+const EXPORTED_UPDATES_KEYS: &[&str] = &[KEY_PENDING_UPDATES];
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(default)]
+struct ExportedState {
+    meta: BTreeMap<String, Value>,
+    experiments: BTreeMap<String, Value>,
+    enrollments: BTreeMap<String, Value>,
+    updates: BTreeMap<String, Value>,
+}
+
+/// Read the values of `keys` from `store`, skipping the ones which aren't set.
+fn get_values<'r>(
+    store: &SingleStore,
+    reader: &'r impl Readable<'r>,
+    keys: &[&str],
+) -> Result<BTreeMap<String, Value>> {
+    let mut values = BTreeMap::new();
+    for key in keys {
+        if let Some(value) = store.get::<Value, _>(reader, key)? {
+            values.insert(key.to_string(), value);
+        }
+    }
+    Ok(values)
+}
+
+/// Set the values of `keys` in `store` to the ones in `values`, deleting the
+/// ones which `values` doesn't have.
+fn replace_values(
+    store: &SingleStore,
+    writer: &mut Writer,
+    keys: &[&str],
+    values: &BTreeMap<String, Value>,
+) -> Result<()> {
+    for key in keys {
+        match values.get(*key) {
+            Some(value) => store.put(writer, key, value)?,
+            None => {
+                if store.get::<Value, _>(writer, key)?.is_some() {
+                    store.delete(writer, key)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Export the contents of the database as a JSON document.
+///
+/// Only the user's choices, the experiments and their enrollments, and the pending
+/// updates are exported. Enrollment ids are replaced with the nil id.
+pub fn export_state<'r>(db: &Database, reader: &'r impl Readable<'r>) -> Result<String> {
+    let mut enrollments = BTreeMap::new();
+    for (slug, enrollment) in db
+        .get_store(StoreId::Enrollments)
+        .collect_all_with_keys::<ExperimentEnrollment, _>(reader)?
+    {
+        let redacted = ExperimentEnrollment {
+            status: enrollment.status.clone_with_nil_enrollment_id(),
+            ..enrollment
+        };
+        enrollments.insert(slug, serde_json::to_value(&redacted)?);
+    }
+    let state = ExportedState {
+        meta: get_values(db.get_store(StoreId::Meta), reader, EXPORTED_META_KEYS)?,
+        experiments: db
+            .get_store(StoreId::Experiments)
+            .collect_all_with_keys(reader)?
+            .into_iter()
+            .collect(),
+        enrollments,
+        updates: get_values(
+            db.get_store(StoreId::Updates),
+            reader,
+            EXPORTED_UPDATES_KEYS,
+        )?,
+    };
+    Ok(serde_json::to_string_pretty(&state)?)
+}
+
+/// Replace the contents of the database with those of a document produced by
+/// `export_state`, within the transaction of `writer`.
+///
+/// The document must have been exported from a database of the same version. Keys
+/// which `export_state` doesn't export are ignored, and keep their current values.
+pub fn import_state(db: &Database, writer: &mut Writer, exported: &str) -> Result<()> {
+    let state: ExportedState = serde_json::from_str(exported)?;
+    let version = state.meta.get(DB_KEY_DB_VERSION).and_then(Value::as_u64);
+    if version != Some(u64::from(DB_VERSION)) {
+        return Err(NimbusError::InvalidExportedState(format!(
+            "expected database version {}, found {:?}",
+            DB_VERSION, version
+        )));
+    }
+    // Make sure we can read what we're about to write.
+    for experiment in state.experiments.values() {
+        serde_json::from_value::<Experiment>(experiment.clone())?;
+    }
+    for enrollment in state.enrollments.values() {
+        serde_json::from_value::<ExperimentEnrollment>(enrollment.clone())?;
+    }
+
+    replace_values(
+        db.get_store(StoreId::Meta),
+        writer,
+        EXPORTED_META_KEYS,
+        &state.meta,
+    )?;
+    replace_values(
+        db.get_store(StoreId::Updates),
+        writer,
+        EXPORTED_UPDATES_KEYS,
+        &state.updates,
+    )?;
+    let stores = vec![
+        (StoreId::Experiments, state.experiments),
+        (StoreId::Enrollments, state.enrollments),
+    ];
+    for (store_id, items) in stores {
+        let store = db.get_store(store_id);
+        store.clear(writer)?;
+        for (key, value) in items {
+            store.put(writer, &key, &value)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dbcache::DB_KEY_GENERATION;
+    use crate::enrollment::{EnrolledReason, EnrollmentStatus};
+    use crate::updating::DB_KEY_BACKOFF_DEADLINE;
+    use crate::DB_KEY_NIMBUS_ID;
+    use serde_json::json;
+    use tempdir::TempDir;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_export_import_roundtrip() -> Result<()> {
+        let _ = env_logger::try_init();
+        let tmp_dir = TempDir::new("test_export_import_roundtrip")?;
+        let db = Database::new(tmp_dir.path().join("from"))?;
+        let mut writer = db.write()?;
+        let meta_store = db.get_store(StoreId::Meta);
+        meta_store.put(&mut writer, DB_KEY_NIMBUS_ID, &Uuid::new_v4())?;
+        meta_store.put(&mut writer, "user-opt-in", &false)?;
+        meta_store.put(&mut writer, DB_KEY_BACKOFF_DEADLINE, &1234u64)?;
+        meta_store.put(&mut writer, DB_KEY_GENERATION, &5u64)?;
+        db.get_store(StoreId::Experiments).put(
+            &mut writer,
+            "secure-gold",
+            &Experiment {
+                slug: "secure-gold".to_owned(),
+                ..Default::default()
+            },
+        )?;
+        db.get_store(StoreId::Enrollments).put(
+            &mut writer,
+            "secure-gold",
+            &ExperimentEnrollment {
+                slug: "secure-gold".to_owned(),
+                status: EnrollmentStatus::new_enrolled(EnrolledReason::Qualified, "control", ""),
+            },
+        )?;
+        db.get_store(StoreId::Updates).put(
+            &mut writer,
+            "pending-experiment-updates",
+            &json!([]),
+        )?;
+        db.get_store(StoreId::Updates).put(
+            &mut writer,
+            "remote-settings-collection",
+            &json!({"last_modified": 1234}),
+        )?;
+        writer.commit()?;
+
+        let exported = export_state(&db, &db.read()?)?;
+        let exported_json: Value = serde_json::from_str(&exported)?;
+        // Identifiers are redacted, and the client's bookkeeping is left out.
+        assert_eq!(
+            exported_json["meta"],
+            json!({"db_version": DB_VERSION, "user-opt-in": false})
+        );
+        assert_eq!(
+            exported_json["updates"],
+            json!({"pending-experiment-updates": []})
+        );
+        assert_eq!(
+            exported_json["enrollments"]["secure-gold"]["status"]["Enrolled"]["enrollment_id"],
+            json!(Uuid::nil().to_string())
+        );
+
+        // Import into a different database, which has its own nimbus_id.
+        let other_db = Database::new(tmp_dir.path().join("to"))?;
+        let other_nimbus_id = Uuid::new_v4();
+        let mut writer = other_db.write()?;
+        let other_meta_store = other_db.get_store(StoreId::Meta);
+        other_meta_store.put(&mut writer, DB_KEY_NIMBUS_ID, &other_nimbus_id)?;
+        other_meta_store.put(&mut writer, DB_KEY_GENERATION, &2u64)?;
+        other_db.get_store(StoreId::Experiments).put(
+            &mut writer,
+            "secure-silver",
+            &Experiment::default(),
+        )?;
+        import_state(&other_db, &mut writer, &exported)?;
+
+        assert_eq!(
+            other_meta_store.get::<Uuid, _>(&writer, DB_KEY_NIMBUS_ID)?,
+            Some(other_nimbus_id)
+        );
+        assert_eq!(
+            other_meta_store.get::<bool, _>(&writer, "user-opt-in")?,
+            Some(false)
+        );
+        assert_eq!(
+            other_meta_store.get::<u64, _>(&writer, DB_KEY_GENERATION)?,
+            Some(2)
+        );
+        assert_eq!(
+            other_meta_store.get::<u64, _>(&writer, DB_KEY_BACKOFF_DEADLINE)?,
+            None
+        );
+        assert_eq!(
+            other_db
+                .get_store(StoreId::Updates)
+                .get::<Value, _>(&writer, "remote-settings-collection")?,
+            None
+        );
+        let experiments: Vec<Experiment> = other_db
+            .get_store(StoreId::Experiments)
+            .collect_all(&writer)?;
+        assert_eq!(experiments.len(), 1);
+        assert_eq!(experiments[0].slug, "secure-gold");
+        let enrollments: Vec<ExperimentEnrollment> = other_db
+            .get_store(StoreId::Enrollments)
+            .collect_all(&writer)?;
+        assert_eq!(enrollments.len(), 1);
+        assert!(enrollments[0].status.is_enrolled());
+        writer.commit()?;
+
+        // Exporting again gives us the same document.
+        assert_eq!(export_state(&other_db, &other_db.read()?)?, exported);
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_rejects_other_versions() -> Result<()> {
+        let tmp_dir = TempDir::new("test_import_rejects_other_versions")?;
+        let db = Database::new(&tmp_dir)?;
+        let mut writer = db.write()?;
+        let exported = json!({
+            "meta": {"db_version": 1},
+            "experiments": {},
+        });
+        assert!(matches!(
+            import_state(&db, &mut writer, &exported.to_string()),
+            Err(NimbusError::InvalidExportedState(_))
+        ));
+        Ok(())
+    }
+}
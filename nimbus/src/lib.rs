@@ -6,36 +6,49 @@ mod dbcache;
 mod enrollment;
 pub mod error;
 mod evaluator;
+mod export;
 pub use error::{NimbusError, Result};
 mod client;
 mod config;
 mod matcher;
+mod observer;
 mod persistence;
 mod sampling;
 mod updating;
 #[cfg(debug_assertions)]
 pub use evaluator::evaluate_enrollment;
 
-use client::{create_client, parse_experiments, SettingsClient};
-pub use config::RemoteSettingsConfig;
+use client::{create_client, parse_experiments, CollectionState, SettingsClient};
+pub use client::{CollectionSignature, ExperimentsMetadata};
+pub use config::{RemoteSettingsCollection, RemoteSettingsConfig};
 use dbcache::DatabaseCache;
-pub use enrollment::EnrollmentStatus;
+pub use enrollment::PreviousExperiment;
 use enrollment::{
-    get_enrollments, get_global_user_participation, opt_in_with_branch, opt_out,
-    set_global_user_participation, EnrollmentChangeEvent, EnrollmentChangeEventType,
-    EnrollmentsEvolver,
+    get_enrollment_statuses, get_global_user_participation, get_previous_enrollments,
+    opt_in_with_branch, opt_out, set_global_user_participation, EnrollmentChangeEvent,
+    EnrollmentChangeEventType, EnrollmentsEvolver, DEFAULT_PREVIOUS_ENROLLMENTS_GC_TIME,
+};
+pub use enrollment::{
+    EnrollmentErrorReason, EnrollmentStatus, EnrollmentStatusReason, EnrollmentStatusType,
+    ExperimentEnrollmentStatus,
 };
 pub use matcher::AppContext;
-use once_cell::sync::OnceCell;
-use persistence::{Database, StoreId, Writer};
+pub use observer::EnrollmentObserver;
+use persistence::{Database, StoreId, Writer, DB_KEY_DB_CORRUPTIONS};
 use serde_derive::*;
+use std::collections::HashSet;
 use std::path::PathBuf;
-use std::sync::Mutex;
-use updating::{read_and_remove_pending_experiments, write_pending_experiments};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use updating::{
+    read_and_remove_pending_experiments, read_backoff_deadline, read_collection_state,
+    read_pending_experiments, write_backoff_deadline, write_collection_state,
+    write_pending_experiments,
+};
 use uuid::Uuid;
 
 const DEFAULT_TOTAL_BUCKETS: u32 = 10000;
-const DB_KEY_NIMBUS_ID: &str = "nimbus-id";
+pub(crate) const DB_KEY_NIMBUS_ID: &str = "nimbus-id";
 
 // The main `NimbusClient` struct must not expose any methods that make an `&mut self`,
 // in order to be compatible with the uniffi `[Threadsafe]` annotation. This is a helper
@@ -44,42 +57,98 @@ const DB_KEY_NIMBUS_ID: &str = "nimbus-id";
 #[derive(Default)]
 struct InternalMutableState {
     available_randomization_units: AvailableRandomizationUnits,
+    max_active_enrollments: Option<u32>,
 }
 
 /// Nimbus is the main struct representing the experiments state
 /// It should hold all the information needed to communicate a specific user's
 /// experimentation status
 pub struct NimbusClient {
-    settings_client: Mutex<Box<dyn SettingsClient + Send>>,
+    // The current settings client. It is only locked to take (or replace) it, and
+    // not for the duration of the requests, which can take a while when retried.
+    settings_client: Mutex<Arc<dyn SettingsClient>>,
     mutable_state: Mutex<InternalMutableState>,
     app_context: AppContext,
-    db: OnceCell<Database>,
+    // The database, opened when it's first needed.
+    db: Mutex<Option<Arc<Database>>>,
     // Manages an in-memory cache so that we can answer certain requests
     // without doing (or waiting for) IO.
     database_cache: DatabaseCache,
-    db_path: PathBuf,
+    // Where the database lives, or `None` to keep it in memory.
+    db_path: Option<PathBuf>,
+    // The observer (if any) to tell about enrollment changes. This is kept
+    // separate from `mutable_state`, and is cloned out of its lock before it's
+    // called, so that we never call out to the observer while holding a lock.
+    enrollment_observer: Mutex<Option<Arc<dyn EnrollmentObserver>>>,
+    // The (experiment slug, enrollment id) pairs we've already delivered an
+    // exposure event for during this session.
+    recorded_exposures: Mutex<HashSet<(String, String)>>,
+    // How long we keep enrollments in experiments which have ended.
+    previous_enrollments_gc_time: Duration,
 }
 
 impl NimbusClient {
     // This constructor *must* not do any kind of I/O since it might be called on the main
-    // thread in the gecko Javascript stack, hence the use of OnceCell for the db.
+    // thread in the gecko Javascript stack, hence the db being opened lazily. The only
+    // exception is reading the small snapshot of the `DatabaseCache`, so that
+    // `get_experiment_branch()` can answer before the database has been initialized.
     pub fn new<P: Into<PathBuf>>(
         app_context: AppContext,
         db_path: P,
         config: Option<RemoteSettingsConfig>,
         available_randomization_units: AvailableRandomizationUnits,
+        previous_enrollments_gc_time_secs: Option<u64>,
+    ) -> Result<Self> {
+        Self::new_with_db_path(
+            app_context,
+            Some(db_path.into()),
+            config,
+            available_randomization_units,
+            previous_enrollments_gc_time_secs,
+        )
+    }
+
+    /// Like `new()`, but the database is kept in memory rather than on disk, so
+    /// nothing is persisted beyond the lifetime of the client.
+    pub fn new_in_memory(
+        app_context: AppContext,
+        config: Option<RemoteSettingsConfig>,
+        available_randomization_units: AvailableRandomizationUnits,
+        previous_enrollments_gc_time_secs: Option<u64>,
+    ) -> Result<Self> {
+        Self::new_with_db_path(
+            app_context,
+            None,
+            config,
+            available_randomization_units,
+            previous_enrollments_gc_time_secs,
+        )
+    }
+
+    fn new_with_db_path(
+        app_context: AppContext,
+        db_path: Option<PathBuf>,
+        config: Option<RemoteSettingsConfig>,
+        available_randomization_units: AvailableRandomizationUnits,
+        previous_enrollments_gc_time_secs: Option<u64>,
     ) -> Result<Self> {
-        let settings_client = Mutex::new(create_client(config)?);
+        let settings_client = Mutex::new(create_client(config)?.into());
         let mutable_state = Mutex::new(InternalMutableState {
             available_randomization_units,
+            max_active_enrollments: None,
         });
         Ok(Self {
             settings_client,
             mutable_state,
             app_context,
-            database_cache: Default::default(),
-            db_path: db_path.into(),
-            db: OnceCell::default(),
+            database_cache: DatabaseCache::new(db_path.as_deref()),
+            db_path,
+            db: Default::default(),
+            enrollment_observer: Default::default(),
+            recorded_exposures: Default::default(),
+            previous_enrollments_gc_time: previous_enrollments_gc_time_secs
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_PREVIOUS_ENROLLMENTS_GC_TIME),
         })
     }
 
@@ -87,22 +156,101 @@ impl NimbusClient {
         let db = self.db()?;
         // We're not actually going to write, we just want to exclude concurrent writers.
         let writer = db.write()?;
-        self.database_cache.commit_and_update(&db, writer)?;
+        self.commit_and_notify(&db, writer, &[])?;
         Ok(())
     }
 
+    /// Rebuilds the in-memory cache used by `get_experiment_branch()`,
+    /// `get_active_experiments()` and the like if another client sharing the same
+    /// database (eg, in another process) has changed it since this one last did,
+    /// and returns whether it did. The registered observer is told when that happens.
+    ///
+    /// Since the functions using the cache never do IO, applications where several
+    /// processes share the database should call this whenever another process may
+    /// have changed the enrollments, eg, when the application comes back to the
+    /// foreground.
+    ///
+    /// Only the LMDB backend supports several processes sharing a database. With the
+    /// default "safe mode" backend, a client fails with `NimbusError::DatabaseInUse`
+    /// when it opens a database which another client has open.
+    pub fn refresh_cache(&self) -> Result<bool> {
+        let db = self.db()?;
+        if !self.database_cache.is_stale(&db, &db.read()?)? {
+            return Ok(false);
+        }
+        let writer = db.write()?;
+        let refreshed = self.database_cache.update_if_stale(&db, writer)?;
+        if refreshed {
+            if let Some(observer) = self.enrollment_observer() {
+                observer.on_cache_updated();
+            }
+        }
+        Ok(refreshed)
+    }
+
+    /// Register an observer which will be told about every enrollment change,
+    /// replacing any previously registered observer.
+    pub fn register_enrollment_observer(&self, observer: Box<dyn EnrollmentObserver>) {
+        self.enrollment_observer
+            .lock()
+            .unwrap()
+            .replace(observer.into());
+    }
+
+    // Returns the registered observer (if any), without keeping its lock held, so
+    // that the observer may call back into this client.
+    fn enrollment_observer(&self) -> Option<Arc<dyn EnrollmentObserver>> {
+        self.enrollment_observer.lock().unwrap().clone()
+    }
+
     // Note: the contract for this function is that it never blocks on IO.
     pub fn get_experiment_branch(&self, slug: String) -> Result<Option<String>> {
         self.database_cache.get_experiment_branch(&slug)
     }
 
+    /// Record that the user has been exposed to the feature `feature_id`, ie, the
+    /// application has actually applied the branch of the experiment that's
+    /// controlling it.
+    ///
+    /// If we are enrolled in an experiment for that feature, an `ExposureEvent` is
+    /// sent to the registered observer, at most once per enrollment during the
+    /// lifetime of this client. Exposures recorded while no observer is registered
+    /// aren't remembered, so the next one after an observer is registered is still
+    /// reported. Like `get_experiment_branch()`, this never blocks on IO.
+    pub fn record_exposure(&self, feature_id: String) -> Result<()> {
+        let experiment = match self
+            .database_cache
+            .get_enrolled_experiment_by_feature(&feature_id)?
+        {
+            Some(experiment) => experiment,
+            None => return Ok(()),
+        };
+        let observer = match self.enrollment_observer() {
+            Some(observer) => observer,
+            None => return Ok(()),
+        };
+        let is_first_exposure = self
+            .recorded_exposures
+            .lock()
+            .unwrap()
+            .insert((experiment.slug.clone(), experiment.enrollment_id.clone()));
+        if is_first_exposure {
+            observer.on_exposure(ExposureEvent {
+                experiment_slug: experiment.slug,
+                branch_slug: experiment.branch_slug,
+                enrollment_id: experiment.enrollment_id,
+                feature_id,
+            });
+        }
+        Ok(())
+    }
+
+    // Note: like `get_all_experiments()`, this is served from the cache once
+    // `initialize()` has built it from the database.
     pub fn get_experiment_branches(&self, slug: String) -> Result<Vec<Branch>> {
-        Ok(self
-            .get_all_experiments()?
-            .iter()
-            .find(|e| e.slug == slug)
-            .map(|e| e.branches.clone())
-            .ok_or(NimbusError::NoSuchExperiment(slug))?)
+        self.database_cache
+            .get_experiment_branches(&slug)?
+            .ok_or(NimbusError::NoSuchExperiment(slug))
     }
 
     pub fn get_global_user_participation(&self) -> Result<bool> {
@@ -124,28 +272,51 @@ impl NimbusClient {
         // We pass the existing experiments as "updated experiments"
         // to the evolver.
         let nimbus_id = self.read_or_create_nimbus_id(&db, &mut writer)?;
-        let state = self.mutable_state.lock().unwrap();
-        let evolver = EnrollmentsEvolver::new(
-            &nimbus_id,
-            &state.available_randomization_units,
-            &self.app_context,
-        );
-        let events = evolver.evolve_enrollments_in_db(&db, &mut writer, &existing_experiments)?;
-        self.database_cache.commit_and_update(&db, writer)?;
+        let events = {
+            let state = self.mutable_state.lock().unwrap();
+            let evolver = EnrollmentsEvolver::new(
+                &nimbus_id,
+                &state.available_randomization_units,
+                &self.app_context,
+                state.max_active_enrollments.map(|max| max as usize),
+                self.previous_enrollments_gc_time,
+            );
+            evolver.evolve_enrollments_in_db(&db, &mut writer, &existing_experiments)?
+        };
+        self.commit_and_notify(&db, writer, &events)?;
         Ok(events)
     }
 
+    /// Returns the experiments we are enrolled in. Like `get_experiment_branch()`,
+    /// this is served from the cache and never blocks on IO, so it fails with
+    /// `NimbusError::DatabaseNotReady` until either `initialize()` or a snapshot
+    /// of the cache from a previous session has made the cache ready.
     pub fn get_active_experiments(&self) -> Result<Vec<EnrolledExperiment>> {
+        self.database_cache.get_active_experiments()
+    }
+
+    /// Returns the experiments we were enrolled in which have since ended, until
+    /// their enrollments are garbage collected.
+    pub fn get_previous_experiments(&self) -> Result<Vec<PreviousExperiment>> {
         let db = self.db()?;
         let reader = db.read()?;
-        get_enrollments(&db, &reader)
+        get_previous_enrollments(&db, &reader)
     }
 
-    pub fn get_all_experiments(&self) -> Result<Vec<Experiment>> {
+    /// Returns the enrollment status of every experiment we know about, including
+    /// the ones we are not enrolled in and the reason why.
+    pub fn get_enrollment_statuses(&self) -> Result<Vec<ExperimentEnrollmentStatus>> {
         let db = self.db()?;
         let reader = db.read()?;
-        db.get_store(StoreId::Experiments)
-            .collect_all::<Experiment, _>(&reader)
+        get_enrollment_statuses(&db, &reader)
+    }
+
+    // Note: like `get_active_experiments()`, this is served from the cache and
+    // never blocks on IO, but the snapshot of the cache doesn't include all the
+    // experiments, so this fails with `NimbusError::DatabaseNotReady` until
+    // `initialize()` has been called.
+    pub fn get_all_experiments(&self) -> Result<Vec<Experiment>> {
+        self.database_cache.get_all_experiments()
     }
 
     pub fn opt_in_with_branch(
@@ -156,7 +327,7 @@ impl NimbusClient {
         let db = self.db()?;
         let mut writer = db.write()?;
         let result = opt_in_with_branch(&db, &mut writer, &experiment_slug, &branch)?;
-        self.database_cache.commit_and_update(&db, writer)?;
+        self.commit_and_notify(&db, writer, &result)?;
         Ok(result)
     }
 
@@ -164,7 +335,7 @@ impl NimbusClient {
         let db = self.db()?;
         let mut writer = db.write()?;
         let result = opt_out(&db, &mut writer, &experiment_slug)?;
-        self.database_cache.commit_and_update(&db, writer)?;
+        self.commit_and_notify(&db, writer, &result)?;
         Ok(result)
     }
 
@@ -173,17 +344,82 @@ impl NimbusClient {
         self.apply_pending_experiments()
     }
 
+    /// Returns metadata about the collection of experiments on the server, eg, its
+    /// timestamp and number of records, without downloading the experiments.
+    pub fn get_experiments_metadata(&self) -> Result<ExperimentsMetadata> {
+        let db = self.db()?;
+        self.with_settings_client(&db, |client| client.get_experiments_metadata())
+    }
+
     pub fn fetch_experiments(&self) -> Result<()> {
         log::info!("fetching experiments");
-        let settings_client = self.settings_client.lock().unwrap();
-        let new_experiments = settings_client.fetch_experiments()?;
         let db = self.db()?;
+        self.with_settings_client(&db, |client| {
+            let mut state = read_collection_state(&db, &db.read()?)?;
+            let new_experiments = match client.sync_experiments(&mut state)? {
+                Some(new_experiments) => new_experiments,
+                None => {
+                    log::info!("experiments have not changed since the last fetch");
+                    return Ok(());
+                }
+            };
+            // The state of the collection is only meaningful to the settings client
+            // which synced it, so we drop what we fetched if
+            // `set_remote_settings_config()` swapped the client in the meantime, and
+            // don't let it swap the client until we've written it.
+            let current_client = self.settings_client.lock().unwrap();
+            if !Arc::ptr_eq(&*current_client, client) {
+                log::info!("the settings client changed during the fetch, ignoring it");
+                return Ok(());
+            }
+            let mut writer = db.write()?;
+            write_pending_experiments(&db, &mut writer, new_experiments)?;
+            write_collection_state(&db, &mut writer, &state)?;
+            writer.commit()?;
+            Ok(())
+        })
+    }
+
+    /// Changes where experiments are fetched from, eg, to add a collection of
+    /// experiments in preview, taking effect from the next fetch. Passing `None`
+    /// stops fetching experiments from a server. The result of a fetch which is in
+    /// progress is dropped, rather than waited for.
+    pub fn set_remote_settings_config(&self, config: Option<RemoteSettingsConfig>) -> Result<()> {
+        let new_client: Arc<dyn SettingsClient> = create_client(config)?.into();
+        let db = self.db()?;
+        let mut settings_client = self.settings_client.lock().unwrap();
+        // The next fetch must get the whole collections of the new client, rather
+        // than only what changed since the last sync of the previous one.
         let mut writer = db.write()?;
-        write_pending_experiments(&db, &mut writer, new_experiments)?;
+        write_collection_state(&db, &mut writer, &CollectionState::default())?;
         writer.commit()?;
+        *settings_client = new_client;
         Ok(())
     }
 
+    // Make requests with the current settings client, honoring any backoff the
+    // server asked for, even before a restart, and persisting any it asks for now.
+    //
+    // We don't hold the lock on the settings client while `func` runs, so the client
+    // may have been replaced by the time it returns.
+    fn with_settings_client<T>(
+        &self,
+        db: &Database,
+        func: impl FnOnce(&Arc<dyn SettingsClient>) -> Result<T>,
+    ) -> Result<T> {
+        let settings_client = Arc::clone(&self.settings_client.lock().unwrap());
+        let deadline = read_backoff_deadline(db, &db.read()?)?;
+        settings_client.set_backoff_deadline(deadline);
+        let result = func(&settings_client);
+        let new_deadline = settings_client.backoff_deadline();
+        if new_deadline != deadline {
+            let mut writer = db.write()?;
+            write_backoff_deadline(db, &mut writer, new_deadline)?;
+            writer.commit()?;
+        }
+        result
+    }
+
     pub fn apply_pending_experiments(&self) -> Result<Vec<EnrollmentChangeEvent>> {
         log::info!("updating experiment list");
         let db = self.db()?;
@@ -192,15 +428,18 @@ impl NimbusClient {
         Ok(match pending_updates {
             Some(new_experiments) => {
                 let nimbus_id = self.read_or_create_nimbus_id(&db, &mut writer)?;
-                let state = self.mutable_state.lock().unwrap();
-                let evolver = EnrollmentsEvolver::new(
-                    &nimbus_id,
-                    &state.available_randomization_units,
-                    &self.app_context,
-                );
-                let events =
-                    evolver.evolve_enrollments_in_db(&db, &mut writer, &new_experiments)?;
-                self.database_cache.commit_and_update(&db, writer)?;
+                let events = {
+                    let state = self.mutable_state.lock().unwrap();
+                    let evolver = EnrollmentsEvolver::new(
+                        &nimbus_id,
+                        &state.available_randomization_units,
+                        &self.app_context,
+                        state.max_active_enrollments.map(|max| max as usize),
+                        self.previous_enrollments_gc_time,
+                    );
+                    evolver.evolve_enrollments_in_db(&db, &mut writer, &new_experiments)?
+                };
+                self.commit_and_notify(&db, writer, &events)?;
                 events
             }
             // We don't need to writer.commit() here because we haven't done anything.
@@ -208,11 +447,43 @@ impl NimbusClient {
         })
     }
 
+    /// Returns the change events that `apply_pending_experiments()` would emit,
+    /// without applying the pending experiments or writing anything.
+    ///
+    /// Newly generated enrollment ids in the returned events won't match the ones
+    /// actually used when the experiments are applied.
+    pub fn preview_pending_experiments(&self) -> Result<Vec<EnrollmentChangeEvent>> {
+        let db = self.db()?;
+        let reader = db.read()?;
+        let new_experiments = match read_pending_experiments(&db, &reader)? {
+            Some(new_experiments) => new_experiments,
+            None => return Ok(vec![]),
+        };
+        // If we don't have a nimbus_id yet, one will be generated when applying the
+        // experiments; we can't write it here, so preview with a temporary one.
+        let nimbus_id = db
+            .get_store(StoreId::Meta)
+            .get(&reader, DB_KEY_NIMBUS_ID)?
+            .unwrap_or_else(Uuid::new_v4);
+        let state = self.mutable_state.lock().unwrap();
+        let evolver = EnrollmentsEvolver::new(
+            &nimbus_id,
+            &state.available_randomization_units,
+            &self.app_context,
+            state.max_active_enrollments.map(|max| max as usize),
+            self.previous_enrollments_gc_time,
+        );
+        evolver.preview_enrollments_in_db(&db, &reader, &new_experiments)
+    }
+
     pub fn set_experiments_locally(&self, experiments_json: String) -> Result<()> {
         let new_experiments = parse_experiments(&experiments_json)?;
         let db = self.db()?;
         let mut writer = db.write()?;
         write_pending_experiments(&db, &mut writer, new_experiments)?;
+        // These experiments replace the ones from the server, so the next fetch
+        // must get the whole collection again rather than only what changed.
+        write_collection_state(&db, &mut writer, &CollectionState::default())?;
         writer.commit()?;
         Ok(())
     }
@@ -241,7 +512,7 @@ impl NimbusClient {
             // The `nimbus_id` itself is a unique identifier.
             // N.B. we do this last, as a signal that all data has been reset.
             store.delete(&mut writer, DB_KEY_NIMBUS_ID)?;
-            self.database_cache.commit_and_update(&db, writer)?;
+            self.commit_and_notify(&db, writer, &events)?;
         }
         // (No need to commit `writer` if the above check was false, since we didn't change anything)
         let mut state = self.mutable_state.lock().unwrap();
@@ -249,6 +520,17 @@ impl NimbusClient {
         Ok(events)
     }
 
+    /// Limit the number of experiments this client may be enrolled in at the same time.
+    ///
+    /// The limit is applied the next time experiments are evolved (eg, by
+    /// `apply_pending_experiments()`). Existing enrollments are never dropped to
+    /// satisfy it; instead, new experiments won't be enrolled in until there is room.
+    /// Passing `None` removes the limit.
+    pub fn set_max_active_enrollments(&self, max_active_enrollments: Option<u32>) {
+        let mut state = self.mutable_state.lock().unwrap();
+        state.max_active_enrollments = max_active_enrollments;
+    }
+
     pub fn nimbus_id(&self) -> Result<Uuid> {
         let db = self.db()?;
         let mut writer = db.write()?;
@@ -257,6 +539,7 @@ impl NimbusClient {
         // we commit just in case - this is hopefully close to a noop in that
         // case!
         writer.commit()?;
+        db.update_meta_backup();
         Ok(uuid)
     }
 
@@ -281,16 +564,88 @@ impl NimbusClient {
         db.get_store(StoreId::Meta)
             .put(&mut writer, DB_KEY_NIMBUS_ID, uuid)?;
         writer.commit()?;
+        db.update_meta_backup();
         Ok(())
     }
 
-    fn db(&self) -> Result<&Database> {
-        self.db
-            .get_or_try_init(|| Ok(Database::new(&self.db_path)?))
+    /// Returns a JSON document describing the persisted state of this client (the user's
+    /// opt-ins and opt-outs, the experiments, enrollments and pending updates), for bug
+    /// reports. Enrollment ids are redacted, and the nimbus_id is left out.
+    pub fn export_state(&self) -> Result<String> {
+        let db = self.db()?;
+        let reader = db.read()?;
+        export::export_state(&db, &reader)
+    }
+
+    /// Replaces the persisted state of this client with a document produced by
+    /// `export_state()`, eg, to reproduce an issue from a bug report. Only available
+    /// in debug builds.
+    pub fn import_state(&self, exported_state: String) -> Result<()> {
+        if !cfg!(debug_assertions) {
+            return Err(NimbusError::InternalError(
+                "import_state() is only available in debug builds",
+            ));
+        }
+        let db = self.db()?;
+        let mut writer = db.write()?;
+        export::import_state(&db, &mut writer, &exported_state)?;
+        self.commit_and_notify(&db, writer, &[])
+    }
+
+    /// Returns the times (as unix timestamps in seconds) at which the database was
+    /// found to be corrupt and had to be recreated, and forgets them, so that each
+    /// corruption is only reported once.
+    pub fn take_database_corruptions(&self) -> Result<Vec<u64>> {
+        let db = self.db()?;
+        let mut writer = db.write()?;
+        let store = db.get_store(StoreId::Meta);
+        let corruptions = store
+            .get::<Vec<u64>, _>(&writer, DB_KEY_DB_CORRUPTIONS)?
+            .unwrap_or_default();
+        if !corruptions.is_empty() {
+            store.delete(&mut writer, DB_KEY_DB_CORRUPTIONS)?;
+            writer.commit()?;
+            db.update_meta_backup();
+        }
+        Ok(corruptions)
+    }
+
+    // Commits `writer` via the database cache, then tells the registered
+    // observer (if any) about `events` and, if it was refreshed, the cache.
+    fn commit_and_notify(
+        &self,
+        db: &Database,
+        writer: Writer,
+        events: &[EnrollmentChangeEvent],
+    ) -> Result<()> {
+        let cache_updated = self.database_cache.commit_and_update(db, writer)?;
+        db.update_meta_backup();
+        if let Some(observer) = self.enrollment_observer() {
+            if !events.is_empty() {
+                observer.on_enrollment_changes(events.to_vec());
+            }
+            if cache_updated {
+                observer.on_cache_updated();
+            }
+        }
+        Ok(())
+    }
+
+    fn db(&self) -> Result<Arc<Database>> {
+        let mut db = self.db.lock().unwrap();
+        if let Some(db) = &*db {
+            return Ok(Arc::clone(db));
+        }
+        let new_db = Arc::new(match &self.db_path {
+            Some(db_path) => Database::new(db_path)?,
+            None => Database::new_in_memory()?,
+        });
+        db.replace(Arc::clone(&new_db));
+        Ok(new_db)
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct EnrolledExperiment {
     pub feature_ids: Vec<String>,
     pub slug: String,
@@ -300,6 +655,16 @@ pub struct EnrolledExperiment {
     pub enrollment_id: String,
 }
 
+/// Describes the user being exposed to the branch of an experiment
+/// they are enrolled in.
+#[derive(Debug, Clone)]
+pub struct ExposureEvent {
+    pub experiment_slug: String,
+    pub branch_slug: String,
+    pub enrollment_id: String,
+    pub feature_id: String,
+}
+
 /// This is the currently supported major schema version.
 pub const SCHEMA_VERSION: u32 = 1;
 // XXX: In the future it would be nice if this lived in its own versioned crate so that
@@ -439,6 +804,8 @@ include!(concat!(env!("OUT_DIR"), "/nimbus.uniffi.rs"));
 mod tests {
     use super::*;
     use enrollment::{EnrolledReason, EnrollmentStatus, ExperimentEnrollment};
+    use std::sync::mpsc;
+    use std::thread;
     use tempdir::TempDir;
 
     #[test]
@@ -457,6 +824,7 @@ mod tests {
                 client_id: Some(mock_client_id.clone()),
                 ..AvailableRandomizationUnits::default()
             },
+            None,
         )?;
 
         let get_client_id = || {
@@ -516,6 +884,568 @@ mod tests {
 
         Ok(())
     }
+
+    #[derive(Clone, Default)]
+    struct TestObserver {
+        events: std::sync::Arc<Mutex<Vec<EnrollmentChangeEvent>>>,
+        cache_updates: std::sync::Arc<Mutex<u32>>,
+        exposures: std::sync::Arc<Mutex<Vec<ExposureEvent>>>,
+    }
+
+    impl EnrollmentObserver for TestObserver {
+        fn on_enrollment_changes(&self, events: Vec<EnrollmentChangeEvent>) {
+            self.events.lock().unwrap().extend(events);
+        }
+
+        fn on_cache_updated(&self) {
+            *self.cache_updates.lock().unwrap() += 1;
+        }
+
+        fn on_exposure(&self, event: ExposureEvent) {
+            self.exposures.lock().unwrap().push(event);
+        }
+    }
+
+    // An experiment which enrolls everyone.
+    fn everyone_experiment_json(slug: &str, feature_id: &str) -> serde_json::Value {
+        serde_json::json!({
+            "data": [{
+                "schemaVersion": "1.0.0",
+                "slug": slug,
+                "branches": [{"slug": "control", "ratio": 1}],
+                "featureIds": [feature_id],
+                "probeSets": [],
+                "bucketConfig": {
+                    "count": 10_000,
+                    "start": 0,
+                    "total": 10_000,
+                    "namespace": slug,
+                    "randomizationUnit": "nimbus_id"
+                },
+                "userFacingName": "Test experiment",
+                "userFacingDescription": "Test experiment",
+                "isEnrollmentPaused": false,
+                "proposedEnrollment": 7,
+            }]
+        })
+    }
+
+    #[test]
+    fn test_enrollment_observer() -> Result<()> {
+        let mock_exp_slug = "exp-1".to_string();
+        let tmp_dir = TempDir::new("test_enrollment_observer")?;
+        let client = NimbusClient::new(
+            AppContext::default(),
+            tmp_dir.path(),
+            None,
+            Default::default(),
+            None,
+        )?;
+        let observer = TestObserver::default();
+        client.register_enrollment_observer(Box::new(observer.clone()));
+
+        client.initialize()?;
+        assert!(observer.events.lock().unwrap().is_empty());
+        assert_eq!(*observer.cache_updates.lock().unwrap(), 1);
+
+        let experiments = everyone_experiment_json(&mock_exp_slug, "feature-1");
+        client.set_experiments_locally(experiments.to_string())?;
+        let returned = client.apply_pending_experiments()?;
+        assert_eq!(returned.len(), 1);
+        {
+            let observed = observer.events.lock().unwrap();
+            assert_eq!(observed.len(), 1);
+            assert_eq!(observed[0].experiment_slug, mock_exp_slug);
+            assert_eq!(observed[0].change, EnrollmentChangeEventType::Enrollment);
+        }
+        assert_eq!(*observer.cache_updates.lock().unwrap(), 2);
+
+        // Applying the same experiments again changes nothing, so the cache isn't
+        // refreshed.
+        client.set_experiments_locally(experiments.to_string())?;
+        assert!(client.apply_pending_experiments()?.is_empty());
+        assert_eq!(observer.events.lock().unwrap().len(), 1);
+        assert_eq!(*observer.cache_updates.lock().unwrap(), 2);
+
+        // Events from other APIs are delivered to the observer too.
+        client.opt_out(mock_exp_slug)?;
+        {
+            let observed = observer.events.lock().unwrap();
+            assert_eq!(observed.len(), 2);
+            assert_eq!(
+                observed[1].change,
+                EnrollmentChangeEventType::Disqualification
+            );
+        }
+        assert_eq!(*observer.cache_updates.lock().unwrap(), 3);
+
+        Ok(())
+    }
+
+    // An observer which calls back into the client that's notifying it.
+    struct ReentrantObserver {
+        client: std::sync::Weak<NimbusClient>,
+        active_experiments: std::sync::Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl ReentrantObserver {
+        fn record_active_experiments(&self) {
+            let client = self.client.upgrade().unwrap();
+            let active = client.get_active_experiments().unwrap();
+            self.active_experiments.lock().unwrap().push(active.len());
+        }
+    }
+
+    impl EnrollmentObserver for ReentrantObserver {
+        fn on_enrollment_changes(&self, _events: Vec<EnrollmentChangeEvent>) {
+            self.record_active_experiments();
+        }
+
+        fn on_cache_updated(&self) {
+            self.record_active_experiments();
+        }
+
+        fn on_exposure(&self, event: ExposureEvent) {
+            self.record_active_experiments();
+            // This exposure has already been reported, so this doesn't recurse.
+            let client = self.client.upgrade().unwrap();
+            client.record_exposure(event.feature_id).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_enrollment_observer_can_call_client() -> Result<()> {
+        let tmp_dir = TempDir::new("test_enrollment_observer_can_call_client")?;
+        let client = std::sync::Arc::new(NimbusClient::new(
+            AppContext::default(),
+            tmp_dir.path(),
+            None,
+            Default::default(),
+            None,
+        )?);
+        let active_experiments = std::sync::Arc::new(Mutex::new(Vec::new()));
+        client.register_enrollment_observer(Box::new(ReentrantObserver {
+            client: std::sync::Arc::downgrade(&client),
+            active_experiments: active_experiments.clone(),
+        }));
+        client.initialize()?;
+
+        let experiments = everyone_experiment_json("exp-1", "feature-1");
+        client.set_experiments_locally(experiments.to_string())?;
+        client.apply_pending_experiments()?;
+        client.record_exposure("feature-1".to_string())?;
+
+        // One call for `initialize()`, two for applying the experiments (the
+        // changes, then the cache update), and one for the exposure.
+        assert_eq!(*active_experiments.lock().unwrap(), vec![0, 1, 1, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_preview_pending_experiments() -> Result<()> {
+        let mock_exp_slug = "exp-1".to_string();
+        let tmp_dir = TempDir::new("test_preview_pending_experiments")?;
+        let client = NimbusClient::new(
+            AppContext::default(),
+            tmp_dir.path(),
+            None,
+            Default::default(),
+            None,
+        )?;
+        let observer = TestObserver::default();
+        client.register_enrollment_observer(Box::new(observer.clone()));
+        client.initialize()?;
+
+        // Nothing pending, nothing to preview.
+        assert!(client.preview_pending_experiments()?.is_empty());
+
+        let experiments = everyone_experiment_json(&mock_exp_slug, "feature-1");
+        client.set_experiments_locally(experiments.to_string())?;
+        let previewed = client.preview_pending_experiments()?;
+        assert_eq!(previewed.len(), 1);
+        assert_eq!(previewed[0].experiment_slug, mock_exp_slug);
+        assert_eq!(previewed[0].change, EnrollmentChangeEventType::Enrollment);
+
+        // Previewing didn't change anything.
+        assert!(client.get_active_experiments()?.is_empty());
+        assert!(observer.events.lock().unwrap().is_empty());
+        assert_eq!(client.preview_pending_experiments()?.len(), 1);
+
+        // And the pending experiments can still be applied, with the same outcome.
+        let applied = client.apply_pending_experiments()?;
+        assert_eq!(applied.len(), 1);
+        assert_eq!(applied[0].experiment_slug, previewed[0].experiment_slug);
+        assert_eq!(applied[0].branch_slug, previewed[0].branch_slug);
+        assert!(client.preview_pending_experiments()?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_exposure() -> Result<()> {
+        let mock_exp_slug = "exp-1".to_string();
+        let mock_feature_id = "feature-1".to_string();
+        let tmp_dir = TempDir::new("test_record_exposure")?;
+        let client = NimbusClient::new(
+            AppContext::default(),
+            tmp_dir.path(),
+            None,
+            Default::default(),
+            None,
+        )?;
+        let observer = TestObserver::default();
+        client.initialize()?;
+
+        let experiments = everyone_experiment_json(&mock_exp_slug, &mock_feature_id);
+        client.set_experiments_locally(experiments.to_string())?;
+        client.apply_pending_experiments()?;
+        let enrollment_id = client.get_active_experiments()?[0].enrollment_id.clone();
+
+        // Exposures recorded before an observer is registered don't stop the next
+        // one from being reported.
+        client.record_exposure(mock_feature_id.clone())?;
+        client.register_enrollment_observer(Box::new(observer.clone()));
+
+        // Nothing is recorded for a feature we're not experimenting on.
+        client.record_exposure("feature-2".to_string())?;
+        assert!(observer.exposures.lock().unwrap().is_empty());
+
+        client.record_exposure(mock_feature_id.clone())?;
+        {
+            let exposures = observer.exposures.lock().unwrap();
+            assert_eq!(exposures.len(), 1);
+            assert_eq!(exposures[0].experiment_slug, mock_exp_slug);
+            assert_eq!(exposures[0].branch_slug, "control");
+            assert_eq!(exposures[0].enrollment_id, enrollment_id);
+            assert_eq!(exposures[0].feature_id, mock_feature_id);
+        }
+
+        // Subsequent exposures to the same enrollment are not reported again.
+        client.record_exposure(mock_feature_id.clone())?;
+        assert_eq!(observer.exposures.lock().unwrap().len(), 1);
+
+        // Once we're no longer enrolled, there's nothing to be exposed to.
+        client.opt_out(mock_exp_slug)?;
+        client.record_exposure(mock_feature_id)?;
+        assert_eq!(observer.exposures.lock().unwrap().len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_snapshot() -> Result<()> {
+        let mock_exp_slug = "exp-1".to_string();
+        let tmp_dir = TempDir::new("test_cache_snapshot")?;
+        let new_client = || {
+            NimbusClient::new(
+                AppContext::default(),
+                tmp_dir.path(),
+                None,
+                Default::default(),
+                None,
+            )
+        };
+        // Without a snapshot, nothing can be answered before initialization.
+        let client = new_client()?;
+        assert!(matches!(
+            client.get_experiment_branch(mock_exp_slug.clone()),
+            Err(NimbusError::DatabaseNotReady)
+        ));
+        let experiments = everyone_experiment_json(&mock_exp_slug, "feature-1");
+        client.set_experiments_locally(experiments.to_string())?;
+        client.apply_pending_experiments()?;
+        drop(client);
+
+        // The next client can answer from the snapshot before the database is opened.
+        let client = new_client()?;
+        assert!(client.db.lock().unwrap().is_none());
+        assert_eq!(
+            client.get_experiment_branch(mock_exp_slug.clone())?,
+            Some("control".to_owned())
+        );
+        assert_eq!(client.get_active_experiments()?.len(), 1);
+        // The snapshot doesn't hold all the experiments though.
+        assert!(matches!(
+            client.get_all_experiments(),
+            Err(NimbusError::DatabaseNotReady)
+        ));
+        assert!(client.db.lock().unwrap().is_none());
+        client.initialize()?;
+        assert_eq!(
+            client.get_experiment_branch(mock_exp_slug)?,
+            Some("control".to_owned())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_remote_settings_config() -> Result<()> {
+        let client =
+            NimbusClient::new_in_memory(AppContext::default(), None, Default::default(), None)?;
+        client.initialize()?;
+        client.fetch_experiments()?;
+        client.apply_pending_experiments()?;
+        assert!(client.get_all_experiments()?.is_empty());
+
+        let db = client.db()?;
+        let mut writer = db.write()?;
+        let state = CollectionState {
+            last_modified: Some(10),
+            ..Default::default()
+        };
+        write_collection_state(&db, &mut writer, &state)?;
+        writer.commit()?;
+
+        let mut dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        dir.push("tests/experiments");
+        client.set_remote_settings_config(Some(RemoteSettingsConfig {
+            server_url: url::Url::from_file_path(dir).unwrap().to_string(),
+            bucket_name: "doesn't matter".to_string(),
+            collection_name: "doesn't matter".to_string(),
+            override_collections: vec![],
+            root_hash: None,
+        }))?;
+        // The state of the previous collection is forgotten.
+        assert_eq!(
+            read_collection_state(&db, &db.read()?)?,
+            CollectionState::default()
+        );
+        client.fetch_experiments()?;
+        client.apply_pending_experiments()?;
+        assert_eq!(client.get_all_experiments()?.len(), 1);
+
+        client.set_remote_settings_config(None)?;
+        client.fetch_experiments()?;
+        client.apply_pending_experiments()?;
+        assert!(client.get_all_experiments()?.is_empty());
+
+        // There are no collections to override on the file system.
+        let mut dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        dir.push("tests/experiments");
+        assert!(matches!(
+            client.set_remote_settings_config(Some(RemoteSettingsConfig {
+                server_url: url::Url::from_file_path(dir).unwrap().to_string(),
+                bucket_name: "doesn't matter".to_string(),
+                collection_name: "doesn't matter".to_string(),
+                override_collections: vec![RemoteSettingsCollection {
+                    bucket_name: "main".to_string(),
+                    collection_name: "nimbus-preview".to_string(),
+                }],
+                root_hash: None,
+            })),
+            Err(NimbusError::InvalidConfig(_))
+        ));
+        Ok(())
+    }
+
+    // A settings client which blocks fetches until it's told to carry on.
+    struct BlockingClient {
+        fetching: Mutex<mpsc::Sender<()>>,
+        carry_on: Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl SettingsClient for BlockingClient {
+        fn get_experiments_metadata(&self) -> Result<ExperimentsMetadata> {
+            Ok(Default::default())
+        }
+        fn fetch_experiments(&self) -> Result<Vec<Experiment>> {
+            self.fetching.lock().unwrap().send(()).unwrap();
+            self.carry_on.lock().unwrap().recv().unwrap();
+            Ok(vec![Experiment::default()])
+        }
+    }
+
+    #[test]
+    fn test_set_remote_settings_config_during_fetch() -> Result<()> {
+        let client = Arc::new(NimbusClient::new_in_memory(
+            AppContext::default(),
+            None,
+            Default::default(),
+            None,
+        )?);
+        let (fetching_tx, fetching_rx) = mpsc::channel();
+        let (carry_on_tx, carry_on_rx) = mpsc::channel();
+        *client.settings_client.lock().unwrap() = Arc::new(BlockingClient {
+            fetching: Mutex::new(fetching_tx),
+            carry_on: Mutex::new(carry_on_rx),
+        });
+        let fetch = {
+            let client = Arc::clone(&client);
+            thread::spawn(move || client.fetch_experiments())
+        };
+        fetching_rx.recv().unwrap();
+
+        // Changing the config doesn't wait for the fetch, which could take a while
+        // if its requests are retried.
+        let (swapped_tx, swapped_rx) = mpsc::channel();
+        {
+            let client = Arc::clone(&client);
+            thread::spawn(move || swapped_tx.send(client.set_remote_settings_config(None)));
+        }
+        swapped_rx
+            .recv_timeout(Duration::from_secs(10))
+            .expect("the config should be changed during the fetch")?;
+
+        // The experiments from the previous client are dropped.
+        carry_on_tx.send(()).unwrap();
+        fetch.join().unwrap()?;
+        let db = client.db()?;
+        assert!(read_pending_experiments(&db, &db.read()?)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_backoff_persists_across_restarts() -> Result<()> {
+        viaduct_reqwest::use_reqwest_backend();
+        let m = mockito::mock(
+            "HEAD",
+            "/buckets/main/collections/messaging-experiments/records",
+        )
+        .with_status(503)
+        .with_header("Retry-After", "3600")
+        .create();
+        let tmp_dir = TempDir::new("test_backoff_persists_across_restarts")?;
+        let new_client = || {
+            NimbusClient::new(
+                AppContext::default(),
+                tmp_dir.path(),
+                Some(RemoteSettingsConfig {
+                    server_url: mockito::server_url(),
+                    bucket_name: "main".to_string(),
+                    collection_name: "messaging-experiments".to_string(),
+                    override_collections: vec![],
+                    root_hash: None,
+                }),
+                Default::default(),
+                None,
+            )
+        };
+        let client = new_client()?;
+        assert!(matches!(
+            client.fetch_experiments(),
+            Err(NimbusError::ServerResponseError(503, _))
+        ));
+        drop(client);
+
+        // After a restart, the new client still backs off, without asking the server.
+        let client = new_client()?;
+        assert!(matches!(
+            client.fetch_experiments(),
+            Err(NimbusError::BackoffError(secs)) if secs > 3500
+        ));
+        assert!(matches!(
+            client.get_experiments_metadata(),
+            Err(NimbusError::BackoffError(_))
+        ));
+        m.expect(1).assert();
+
+        // Once the deadline has passed, it asks the server again.
+        let db = client.db()?;
+        let mut writer = db.write()?;
+        write_backoff_deadline(
+            &db,
+            &mut writer,
+            Some(std::time::SystemTime::now() - Duration::from_secs(1)),
+        )?;
+        writer.commit()?;
+        let _m = mockito::mock(
+            "HEAD",
+            "/buckets/main/collections/messaging-experiments/records",
+        )
+        .with_status(404)
+        .create();
+        assert!(matches!(
+            client.fetch_experiments(),
+            Err(NimbusError::ClientResponseError(404, _))
+        ));
+        let reader = db.read()?;
+        assert_eq!(read_backoff_deadline(&db, &reader)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cached_reads() -> Result<()> {
+        let mock_exp_slug = "exp-1".to_string();
+        let tmp_dir = TempDir::new("test_cached_reads")?;
+        let client = NimbusClient::new(
+            AppContext::default(),
+            tmp_dir.path(),
+            None,
+            Default::default(),
+            None,
+        )?;
+        // Reading before initialization fails rather than blocking on IO.
+        assert!(matches!(
+            client.get_all_experiments(),
+            Err(NimbusError::DatabaseNotReady)
+        ));
+        assert!(matches!(
+            client.get_active_experiments(),
+            Err(NimbusError::DatabaseNotReady)
+        ));
+        client.initialize()?;
+        assert!(client.get_all_experiments()?.is_empty());
+        let experiments = everyone_experiment_json(&mock_exp_slug, "feature-1");
+        client.set_experiments_locally(experiments.to_string())?;
+        client.apply_pending_experiments()?;
+        assert_eq!(client.get_active_experiments()?.len(), 1);
+        assert_eq!(client.get_all_experiments()?.len(), 1);
+        assert_eq!(
+            client.get_experiment_branches(mock_exp_slug.clone())?.len(),
+            1
+        );
+        assert!(matches!(
+            client.get_experiment_branches("no-such-experiment".to_owned()),
+            Err(NimbusError::NoSuchExperiment(_))
+        ));
+
+        // Change the database behind the cache's back: the reads are still served
+        // from the cache, until it's rebuilt.
+        let db = client.db()?;
+        let mut writer = db.write()?;
+        let experiment: Experiment = serde_json::from_value(
+            everyone_experiment_json("exp-2", "feature-2")["data"][0].clone(),
+        )?;
+        db.get_store(StoreId::Experiments)
+            .put(&mut writer, "exp-2", &experiment)?;
+        writer.commit()?;
+        assert_eq!(client.get_all_experiments()?.len(), 1);
+        assert!(client.get_experiment_branches("exp-2".to_owned()).is_err());
+        client.initialize()?;
+        assert_eq!(client.get_all_experiments()?.len(), 2);
+        assert_eq!(client.get_experiment_branches("exp-2".to_owned())?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_memory_client() -> Result<()> {
+        let mock_exp_slug = "exp-1".to_string();
+        let client =
+            NimbusClient::new_in_memory(AppContext::default(), None, Default::default(), None)?;
+        client.initialize()?;
+        let nimbus_id = client.nimbus_id()?;
+        assert_eq!(client.nimbus_id()?, nimbus_id);
+
+        let experiments = everyone_experiment_json(&mock_exp_slug, "feature-1");
+        client.set_experiments_locally(experiments.to_string())?;
+        client.apply_pending_experiments()?;
+        let active = client.get_active_experiments()?;
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].slug, mock_exp_slug);
+        assert_eq!(
+            client.get_experiment_branch(mock_exp_slug.clone())?,
+            Some("control".to_owned())
+        );
+
+        // A second in-memory client shares nothing with the first.
+        let other =
+            NimbusClient::new_in_memory(AppContext::default(), None, Default::default(), None)?;
+        other.initialize()?;
+        assert_ne!(other.nimbus_id()?, nimbus_id);
+        assert!(other.get_active_experiments()?.is_empty());
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
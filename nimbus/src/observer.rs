@@ -0,0 +1,32 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A callback interface that lets the consuming application learn about
+//! changes to the enrollment state, regardless of which API caused them.
+
+use crate::enrollment::EnrollmentChangeEvent;
+use crate::ExposureEvent;
+
+/// Implemented by the consuming application (typically in the foreign language,
+/// via a uniffi callback interface) and registered with
+/// `NimbusClient::register_enrollment_observer`.
+///
+/// The methods are called synchronously on whichever thread made the call into
+/// nimbus, after the corresponding changes have been committed to the database,
+/// so implementations should return quickly. No locks are held while they're
+/// called, so they may call back into the `NimbusClient` that is notifying them.
+pub trait EnrollmentObserver: Send + Sync {
+    /// Called with the change events produced by a single call into nimbus.
+    /// This is not called when nothing changed.
+    fn on_enrollment_changes(&self, events: Vec<EnrollmentChangeEvent>);
+
+    /// Called every time the in-memory cache used by the non-blocking API
+    /// functions (eg, `get_experiment_branch()`) has been refreshed.
+    fn on_cache_updated(&self);
+
+    /// Called the first time in this session that the user is exposed to the
+    /// branch of an experiment they are enrolled in, as reported by
+    /// `NimbusClient::record_exposure`.
+    fn on_exposure(&self, event: ExposureEvent);
+}
@@ -2,26 +2,65 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-//! Our storage abstraction, currently backed by Rkv.
+//! Our storage abstraction, backed by Rkv or kept in memory.
 
+use crate::enrollment::{
+    now_secs, EnrollmentErrorReason, DB_KEY_EXPERIMENT_OPT_OUTS, DB_KEY_GLOBAL_USER_PARTICIPATION,
+    DB_KEY_LEGACY_EXPERIMENT_OPT_OUTS,
+};
 use crate::error::{NimbusError, Result};
+use crate::DB_KEY_NIMBUS_ID;
 // This uses the lmdb backend for rkv, which is unstable.
 // We use it for now since glean didn't seem to have trouble with it (although
 // it must be noted that the rkv documentation explicitly says "To use rkv in
 // production/release environments at Mozilla, you may do so with the "SafeMode"
 // backend", so we really should get more guidance here.)
-use core::iter::Iterator;
 use rkv::{StoreError, StoreOptions};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 
 // We use an incrementing integer to manage database migrations.
 // If you need to make a backwards-incompatible change to the data schema,
-// increment `DB_VERSION` and implement some migration logic in `maybe_upgrade`.
+// increment `DB_VERSION`, add a step function to `MIGRATIONS` which upgrades the
+// data from the previous version, and add a fixture for the previous version
+// under `tests/fixtures/` which exercises it.
 //
 // ⚠️ Warning : Altering the type of `DB_VERSION` would itself require a DB migration. ⚠️
-const DB_KEY_DB_VERSION: &str = "db_version";
-const DB_VERSION: u16 = 1;
+pub(crate) const DB_KEY_DB_VERSION: &str = "db_version";
+pub(crate) const DB_VERSION: u16 = 2;
+
+// The unix timestamps (in seconds) at which the database was found to be corrupt
+// and recreated, until they are taken for reporting.
+pub(crate) const DB_KEY_DB_CORRUPTIONS: &str = "db-corruptions";
+
+// A small file, next to the database directory, in which we keep a copy of the
+// `Meta` values we must not lose if the database has to be recreated because
+// it is corrupt.
+const META_BACKUP_FILENAME: &str = "meta-backup.json";
+
+// The file, next to the database directory, which the "safe mode" backend locks
+// while the database is open, see `lock_database`.
+#[cfg(feature = "rkv-safe-mode")]
+const LOCK_FILENAME: &str = "db.lock";
+const BACKED_UP_META_KEYS: &[&str] = &[
+    DB_KEY_NIMBUS_ID,
+    DB_KEY_GLOBAL_USER_PARTICIPATION,
+    DB_KEY_EXPERIMENT_OPT_OUTS,
+    DB_KEY_LEGACY_EXPERIMENT_OPT_OUTS,
+    DB_KEY_DB_CORRUPTIONS,
+];
+
+// A step which migrates the database from one version to the next, within the
+// transaction of the writer it is given.
+type MigrationStep = fn(&Database, &mut Writer) -> Result<()>;
+
+// The migration steps, in order: `MIGRATIONS[0]` migrates from version 1 to 2,
+// `MIGRATIONS[1]` from version 2 to 3, and so on. Thus there must always be
+// exactly `DB_VERSION - 1` steps.
+const MIGRATIONS: &[MigrationStep] = &[Database::migrate_v1_to_v2];
 
 // Inspired by Glean - use a feature to choose between the backends.
 // Select the LMDB-powered storage backend when the feature is not activated.
@@ -34,20 +73,24 @@ mod backend {
 
     pub type Rkv = rkv::Rkv<LmdbEnvironment>;
     pub type RkvSingleStore = rkv::SingleStore<LmdbDatabase>;
-    pub type Reader<'t> = rkv::Reader<LmdbRoTransaction<'t>>;
-    pub type Writer<'t> = rkv::Writer<LmdbRwTransaction<'t>>;
-    pub trait Readable<'r>:
+    pub type RkvReader<'t> = rkv::Reader<LmdbRoTransaction<'t>>;
+    pub type RkvWriter<'t> = rkv::Writer<LmdbRwTransaction<'t>>;
+    pub trait RkvReadable<'r>:
         rkv::Readable<'r, Database = LmdbDatabase, RoCursor = LmdbRoCursor<'r>>
     {
     }
     impl<'r, T: rkv::Readable<'r, Database = LmdbDatabase, RoCursor = LmdbRoCursor<'r>>>
-        Readable<'r> for T
+        RkvReadable<'r> for T
     {
     }
 
     pub fn rkv_new(path: &Path) -> Result<Rkv, rkv::StoreError> {
         Rkv::new::<Lmdb>(path)
     }
+
+    // The file the data is kept in, within the database directory.
+    #[cfg(test)]
+    pub const DB_FILENAME: &str = "data.mdb";
 }
 
 // Select the "safe mode" storage backend when the feature is activated.
@@ -61,31 +104,463 @@ mod backend {
 
     pub type Rkv = rkv::Rkv<SafeModeEnvironment>;
     pub type RkvSingleStore = rkv::SingleStore<SafeModeDatabase>;
-    pub type Reader<'t> = rkv::Reader<SafeModeRoTransaction<'t>>;
-    pub type Writer<'t> = rkv::Writer<SafeModeRwTransaction<'t>>;
-    pub trait Readable<'r>:
+    pub type RkvReader<'t> = rkv::Reader<SafeModeRoTransaction<'t>>;
+    pub type RkvWriter<'t> = rkv::Writer<SafeModeRwTransaction<'t>>;
+    pub trait RkvReadable<'r>:
         rkv::Readable<'r, Database = SafeModeDatabase, RoCursor = SafeModeRoCursor<'r>>
     {
     }
     impl<
             'r,
             T: rkv::Readable<'r, Database = SafeModeDatabase, RoCursor = SafeModeRoCursor<'r>>,
-        > Readable<'r> for T
+        > RkvReadable<'r> for T
     {
     }
 
     pub fn rkv_new(path: &Path) -> Result<Rkv, rkv::StoreError> {
         Rkv::new::<SafeMode>(path)
     }
+
+    // The file the data is kept in, within the database directory.
+    #[cfg(test)]
+    pub const DB_FILENAME: &str = "data.safe.bin";
 }
 
 use backend::*;
-pub use backend::{Readable, Writer};
+
+/// A storage backend for a `Database`, which hands out read-only and read-write
+/// transactions over the stores listed in `StoreId`.
+///
+/// Rkv is what we use in production; the in-memory backend keeps nothing on
+/// disk, which is useful for tests and for embedders which don't want Nimbus
+/// to persist anything. Since transactions address stores by their `StoreId`,
+/// a store can't be used with a transaction from another backend.
+trait Backend: Send + Sync {
+    fn read(&self) -> Result<Box<dyn Transaction + '_>>;
+    fn write(&self) -> Result<Box<dyn WriteTransaction + '_>>;
+}
+
+/// The operations available in any transaction of a `Backend`.
+trait Transaction {
+    /// Read the raw JSON stored under `key`.
+    fn get_json(&self, store: StoreId, key: &str) -> Result<Option<String>>;
+    /// Read all the keys and raw JSON values of the store, ordered by key.
+    fn get_all_json(&self, store: StoreId) -> Result<Vec<(String, String)>>;
+}
+
+/// The operations available in read-write transactions of a `Backend`.
+trait WriteTransaction: Transaction {
+    fn put_json(&mut self, store: StoreId, key: &str, json: &str) -> Result<()>;
+    /// Delete `key`, failing if it doesn't exist.
+    fn delete(&mut self, store: StoreId, key: &str) -> Result<()>;
+    fn clear(&mut self, store: StoreId) -> Result<()>;
+    fn commit(self: Box<Self>) -> Result<()>;
+}
+
+/// The rkv environment of a database, and its stores.
+struct RkvBackend {
+    rkv: Rkv,
+    meta_store: RkvSingleStore,
+    experiment_store: RkvSingleStore,
+    enrollment_store: RkvSingleStore,
+    updates_store: RkvSingleStore,
+    // Held for as long as the database is open, see `lock_database`.
+    _lock_file: Option<fs::File>,
+}
+
+// The "safe mode" backend reads the whole database file when it's opened, and
+// writes all of it back on each commit, without any locking. Several instances
+// sharing the file, whether in the same process or not, would thus silently
+// overwrite each other's changes, so we only let one of them open it at a time,
+// by holding an exclusive lock on a file next to it.
+#[cfg(feature = "rkv-safe-mode")]
+fn lock_database(path: &Path) -> Result<Option<fs::File>> {
+    use fs2::FileExt;
+    let lock_file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(path.join(LOCK_FILENAME))?;
+    match lock_file.try_lock_exclusive() {
+        Ok(()) => Ok(Some(lock_file)),
+        Err(e) if e.kind() == fs2::lock_contended_error().kind() => Err(NimbusError::DatabaseInUse),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// LMDB does its own locking, and supports several processes sharing a database.
+#[cfg(not(feature = "rkv-safe-mode"))]
+fn lock_database(_path: &Path) -> Result<Option<fs::File>> {
+    Ok(None)
+}
+
+impl RkvBackend {
+    /// Open the rkv database under `path`, also returning whether it had to be
+    /// recreated because it was corrupt.
+    fn open<P: AsRef<Path>>(path: P) -> Result<(Self, bool)> {
+        fs::create_dir_all(path.as_ref())?;
+        // Before anything else, so that recreating a corrupt database is exclusive too.
+        let lock_file = lock_database(path.as_ref())?;
+        let path = std::path::Path::new(path.as_ref()).join("db");
+        log::debug!("Database path: {:?}", path.display());
+        fs::create_dir_all(&path)?;
+        let mut was_recreated = false;
+        let rkv = match rkv_new(&path) {
+            Ok(rkv) => Ok(rkv),
+            Err(rkv_error) => {
+                match rkv_error {
+                    // For some errors we just delete the DB and start again.
+                    StoreError::DatabaseCorrupted | StoreError::FileInvalid => {
+                        // On one hand this seems a little dangerous, but on
+                        // the other hand avoids us knowing about the
+                        // underlying implementation (ie, how do we know what
+                        // files might exist in all cases?)
+                        log::warn!(
+                            "Database at '{}' appears corrupt - removing and recreating",
+                            path.display()
+                        );
+                        fs::remove_dir_all(&path)?;
+                        fs::create_dir_all(&path)?;
+                        was_recreated = true;
+                        rkv_new(&path)
+                    }
+                    // All other errors are fatal.
+                    _ => Err(rkv_error),
+                }
+            }
+        }?;
+        let backend = Self {
+            meta_store: rkv.open_single("meta", StoreOptions::create())?,
+            experiment_store: rkv.open_single("experiments", StoreOptions::create())?,
+            enrollment_store: rkv.open_single("enrollments", StoreOptions::create())?,
+            updates_store: rkv.open_single("updates", StoreOptions::create())?,
+            rkv,
+            _lock_file: lock_file,
+        };
+        log::debug!("Database initialized");
+        Ok((backend, was_recreated))
+    }
+
+    fn store(&self, store_id: StoreId) -> &RkvSingleStore {
+        match store_id {
+            StoreId::Meta => &self.meta_store,
+            StoreId::Experiments => &self.experiment_store,
+            StoreId::Enrollments => &self.enrollment_store,
+            StoreId::Updates => &self.updates_store,
+        }
+    }
+}
+
+impl Backend for RkvBackend {
+    fn read(&self) -> Result<Box<dyn Transaction + '_>> {
+        Ok(Box::new(RkvTransaction {
+            backend: self,
+            reader: self.rkv.read()?,
+        }))
+    }
+
+    fn write(&self) -> Result<Box<dyn WriteTransaction + '_>> {
+        Ok(Box::new(RkvWriteTransaction {
+            backend: self,
+            writer: self.rkv.write()?,
+        }))
+    }
+}
+
+struct RkvTransaction<'t> {
+    backend: &'t RkvBackend,
+    reader: RkvReader<'t>,
+}
+
+impl<'t> Transaction for RkvTransaction<'t> {
+    fn get_json(&self, store: StoreId, key: &str) -> Result<Option<String>> {
+        rkv_read_json(self.backend.store(store), &self.reader, key)
+    }
+
+    fn get_all_json(&self, store: StoreId) -> Result<Vec<(String, String)>> {
+        rkv_read_all_json(self.backend.store(store), &self.reader)
+    }
+}
+
+struct RkvWriteTransaction<'t> {
+    backend: &'t RkvBackend,
+    writer: RkvWriter<'t>,
+}
+
+impl<'t> Transaction for RkvWriteTransaction<'t> {
+    fn get_json(&self, store: StoreId, key: &str) -> Result<Option<String>> {
+        rkv_read_json(self.backend.store(store), &self.writer, key)
+    }
+
+    fn get_all_json(&self, store: StoreId) -> Result<Vec<(String, String)>> {
+        rkv_read_all_json(self.backend.store(store), &self.writer)
+    }
+}
+
+impl<'t> WriteTransaction for RkvWriteTransaction<'t> {
+    fn put_json(&mut self, store: StoreId, key: &str, json: &str) -> Result<()> {
+        self.backend
+            .store(store)
+            .put(&mut self.writer, key, &rkv::Value::Json(json))?;
+        Ok(())
+    }
+
+    fn delete(&mut self, store: StoreId, key: &str) -> Result<()> {
+        self.backend.store(store).delete(&mut self.writer, key)?;
+        Ok(())
+    }
+
+    fn clear(&mut self, store: StoreId) -> Result<()> {
+        self.backend.store(store).clear(&mut self.writer)?;
+        Ok(())
+    }
+
+    fn commit(self: Box<Self>) -> Result<()> {
+        self.writer.commit()?;
+        Ok(())
+    }
+}
+
+fn rkv_read_json<'r, R: RkvReadable<'r>>(
+    store: &RkvSingleStore,
+    reader: &'r R,
+    key: &str,
+) -> Result<Option<String>> {
+    match store.get(reader, key)? {
+        Some(rkv::Value::Json(data)) => Ok(Some(data.to_owned())),
+        Some(_) => Err(NimbusError::InvalidPersistedData),
+        None => Ok(None),
+    }
+}
+
+fn rkv_read_all_json<'r, R: RkvReadable<'r>>(
+    store: &RkvSingleStore,
+    reader: &'r R,
+) -> Result<Vec<(String, String)>> {
+    let mut result = Vec::new();
+    let mut iter = store.iter_start(reader)?;
+    while let Some(Ok((key, data))) = iter.next() {
+        if let rkv::Value::Json(data) = data {
+            let key = std::str::from_utf8(key).map_err(|_| NimbusError::InvalidPersistedData)?;
+            result.push((key.to_owned(), data.to_owned()));
+        }
+    }
+    Ok(result)
+}
+
+// The contents of each store of the in-memory backend.
+type MemoryStores = HashMap<StoreId, BTreeMap<String, String>>;
+
+/// A backend which keeps all of its data in memory.
+///
+/// Like rkv, it allows many concurrent readers but a single writer: readers
+/// see a snapshot of the data as it was when they were created, and a writer
+/// works on its own copy of the data which replaces the shared one when it is
+/// committed.
+struct MemoryBackend {
+    data: RwLock<Arc<MemoryStores>>,
+    write_lock: Mutex<()>,
+}
+
+impl MemoryBackend {
+    fn new() -> Self {
+        Self {
+            data: RwLock::new(Arc::new(MemoryStores::new())),
+            write_lock: Mutex::new(()),
+        }
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn read(&self) -> Result<Box<dyn Transaction + '_>> {
+        Ok(Box::new(MemoryTransaction {
+            data: Arc::clone(&self.data.read().unwrap()),
+        }))
+    }
+
+    fn write(&self) -> Result<Box<dyn WriteTransaction + '_>> {
+        // Take the write lock first, so nobody can commit between us reading the
+        // data and committing our own changes.
+        let guard = self.write_lock.lock().unwrap();
+        let data = MemoryStores::clone(&self.data.read().unwrap());
+        Ok(Box::new(MemoryWriteTransaction {
+            backend: self,
+            data,
+            _guard: guard,
+        }))
+    }
+}
+
+/// A read-only snapshot of the in-memory backend.
+struct MemoryTransaction {
+    data: Arc<MemoryStores>,
+}
+
+impl Transaction for MemoryTransaction {
+    fn get_json(&self, store: StoreId, key: &str) -> Result<Option<String>> {
+        Ok(memory_read_json(&self.data, store, key))
+    }
+
+    fn get_all_json(&self, store: StoreId) -> Result<Vec<(String, String)>> {
+        Ok(memory_read_all_json(&self.data, store))
+    }
+}
+
+/// A transaction on the in-memory backend. Nothing is visible to readers until
+/// it is committed, and dropping it without committing aborts it.
+struct MemoryWriteTransaction<'t> {
+    backend: &'t MemoryBackend,
+    data: MemoryStores,
+    _guard: MutexGuard<'t, ()>,
+}
+
+impl<'t> MemoryWriteTransaction<'t> {
+    fn store_mut(&mut self, store: StoreId) -> &mut BTreeMap<String, String> {
+        self.data.entry(store).or_default()
+    }
+}
+
+impl<'t> Transaction for MemoryWriteTransaction<'t> {
+    fn get_json(&self, store: StoreId, key: &str) -> Result<Option<String>> {
+        Ok(memory_read_json(&self.data, store, key))
+    }
+
+    fn get_all_json(&self, store: StoreId) -> Result<Vec<(String, String)>> {
+        Ok(memory_read_all_json(&self.data, store))
+    }
+}
+
+impl<'t> WriteTransaction for MemoryWriteTransaction<'t> {
+    fn put_json(&mut self, store: StoreId, key: &str, json: &str) -> Result<()> {
+        self.store_mut(store)
+            .insert(key.to_owned(), json.to_owned());
+        Ok(())
+    }
+
+    fn delete(&mut self, store: StoreId, key: &str) -> Result<()> {
+        // rkv fails to delete a key which doesn't exist, so we do too.
+        match self.store_mut(store).remove(key) {
+            Some(_) => Ok(()),
+            None => Err(StoreError::KeyValuePairNotFound.into()),
+        }
+    }
+
+    fn clear(&mut self, store: StoreId) -> Result<()> {
+        self.store_mut(store).clear();
+        Ok(())
+    }
+
+    fn commit(self: Box<Self>) -> Result<()> {
+        // Keep holding the write lock until the new data has replaced the old.
+        let MemoryWriteTransaction {
+            backend,
+            data,
+            _guard,
+        } = *self;
+        *backend.data.write().unwrap() = Arc::new(data);
+        Ok(())
+    }
+}
+
+fn memory_read_json(data: &MemoryStores, store: StoreId, key: &str) -> Option<String> {
+    data.get(&store).and_then(|store| store.get(key)).cloned()
+}
+
+fn memory_read_all_json(data: &MemoryStores, store: StoreId) -> Vec<(String, String)> {
+    data.get(&store)
+        .map(|store| {
+            store
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// A "reader", used for read-only transactions.
+pub struct Reader<'t> {
+    transaction: Box<dyn Transaction + 't>,
+}
+
+/// A "writer", used for read-write transactions. `writer.commit()` must be
+/// called for its changes to be persisted.
+pub struct Writer<'t> {
+    transaction: Box<dyn WriteTransaction + 't>,
+    // The stores which have been written to in this transaction.
+    modified_stores: HashSet<StoreId>,
+}
+
+impl<'t> Writer<'t> {
+    fn new(transaction: Box<dyn WriteTransaction + 't>) -> Self {
+        Self {
+            transaction,
+            modified_stores: HashSet::new(),
+        }
+    }
+
+    pub fn commit(self) -> Result<()> {
+        self.transaction.commit()
+    }
+
+    /// Whether anything has been put in, deleted from or cleared from `store_id`
+    /// in this transaction, whether or not it actually changed its contents.
+    pub fn has_modified(&self, store_id: StoreId) -> bool {
+        self.modified_stores.contains(&store_id)
+    }
+}
+
+/// Implemented by both readers and writers, so that data can be read either
+/// outside of a transaction or from within one (ie, so we can see what we've
+/// written to the transaction before it's committed).
+pub trait Readable<'r> {
+    /// Read the raw JSON stored under `key`.
+    fn read_json(&'r self, store: &SingleStore, key: &str) -> Result<Option<String>>;
+    /// Read all the keys and raw JSON values of the store, ordered by key.
+    fn read_all_json(&'r self, store: &SingleStore) -> Result<Vec<(String, String)>>;
+}
+
+impl<'r, 't: 'r> Readable<'r> for Reader<'t> {
+    fn read_json(&'r self, store: &SingleStore, key: &str) -> Result<Option<String>> {
+        self.transaction.get_json(store.id, key)
+    }
+
+    fn read_all_json(&'r self, store: &SingleStore) -> Result<Vec<(String, String)>> {
+        self.transaction.get_all_json(store.id)
+    }
+}
+
+impl<'r, 't: 'r> Readable<'r> for Writer<'t> {
+    fn read_json(&'r self, store: &SingleStore, key: &str) -> Result<Option<String>> {
+        self.transaction.get_json(store.id, key)
+    }
+
+    fn read_all_json(&'r self, store: &SingleStore) -> Result<Vec<(String, String)>> {
+        self.transaction.get_all_json(store.id)
+    }
+}
+
+/// Write `contents` to `path` through a temporary file, so that we never leave a
+/// truncated file behind. Each write uses its own temporary file, since other
+/// threads or processes sharing the database may be writing the same file.
+pub(crate) fn write_file_atomically(path: &Path, contents: &str) -> Result<()> {
+    static TMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let tmp_path = path.with_extension(format!(
+        "{}.{}.tmp",
+        std::process::id(),
+        TMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&tmp_path, contents)?;
+    if let Err(e) = fs::rename(&tmp_path, path) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+    Ok(())
+}
 
 /// Enumeration of the different stores within our database.
 ///
 /// Our rkv database contains a number of different "stores", and the items
 /// in each store correspond to a particular type of object at the Rust level.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum StoreId {
     /// Store containing the set of known experiments, as read from the server.
     ///
@@ -111,97 +586,118 @@ pub enum StoreId {
     ///                     current client instance.
     ///   * "user-opt-in":  bool, whether the user has explicitly opted in or out
     ///                     of participating in experiments.
+    ///   * "opted-out-experiments":  Map<String, Option<u64>>, the slugs of the
+    ///                     experiments the user has explicitly opted out of, with when
+    ///                     each experiment was seen to end (as a unix timestamp in
+    ///                     seconds) if it has.
+    ///   * "experiment-opt-outs":  Vec<String>, the slugs of the experiments the
+    ///                     user had opted out of, as kept by older versions. Only read
+    ///                     when "opted-out-experiments" doesn't exist yet.
+    ///   * "db-corruptions":  Vec<u64>, when the database was found to be corrupt
+    ///                     and recreated, as unix timestamps in seconds.
+    ///
+    /// The values which must survive the database being recreated are also mirrored
+    /// to a backup file, see `Database::update_meta_backup`.
     Meta,
     /// Store containing pending updates to experiment data.
     ///
-    /// The `Updates` store contains a single key "pending-experiment-updates", whose
+    /// The `Updates` store contains a key "pending-experiment-updates", whose
     /// corresponding value is a serialized `Vec<Experiment>` of new experiment data
     /// that has been received from the server but not yet processed by the application.
+    ///
+    /// It also contains a key "remote-settings-collection", whose value is the
+    /// `CollectionState` of the experiments collection as we last synced it, so that
+    /// we only need to fetch what changed since. Like the pending updates, it can
+    /// always be thrown away, at the cost of fetching the whole collection again.
     Updates,
 }
 
-/// A wrapper for an Rkv store. Implemented to allow any value which supports
-/// serde to be used.
+/// A wrapper for a single store of the database, whichever the backend.
+/// Implemented to allow any value which supports serde to be used.
 pub struct SingleStore {
-    store: RkvSingleStore,
+    id: StoreId,
 }
 
 impl SingleStore {
-    pub fn new(store: RkvSingleStore) -> Self {
-        SingleStore { store }
+    pub fn new(id: StoreId) -> Self {
+        SingleStore { id }
     }
 
     pub fn put<T: serde::Serialize + for<'de> serde::Deserialize<'de>>(
         &self,
-        mut writer: &mut Writer,
+        writer: &mut Writer,
         key: &str,
         persisted_data: &T,
     ) -> Result<()> {
         let persisted_json = serde_json::to_string(persisted_data)?;
-        self.store
-            .put(&mut writer, key, &rkv::Value::Json(&persisted_json))?;
-        Ok(())
+        writer.modified_stores.insert(self.id);
+        writer.transaction.put_json(self.id, key, &persisted_json)
     }
 
-    #[allow(dead_code)]
-    pub fn delete(&self, mut writer: &mut Writer, key: &str) -> Result<()> {
-        self.store.delete(&mut writer, key)?;
-        Ok(())
+    pub fn delete(&self, writer: &mut Writer, key: &str) -> Result<()> {
+        writer.modified_stores.insert(self.id);
+        writer.transaction.delete(self.id, key)
     }
 
-    pub fn clear(&self, mut writer: &mut Writer) -> Result<()> {
-        self.store.clear(&mut writer)?;
-        Ok(())
+    pub fn clear(&self, writer: &mut Writer) -> Result<()> {
+        writer.modified_stores.insert(self.id);
+        writer.transaction.clear(self.id)
     }
 
     // Some "get" functions that cooperate with transactions (ie, so we can
     // get what we've written to the transaction before it's committed).
-    // It's unfortunate that these are duplicated with the DB itself, but the
-    // traits used by rkv make this tricky.
     pub fn get<'r, T, R>(&self, reader: &'r R, key: &str) -> Result<Option<T>>
     where
         R: Readable<'r>,
         T: serde::Serialize + for<'de> serde::Deserialize<'de>,
     {
-        let persisted_data = self.store.get(reader, key)?;
-        match persisted_data {
-            Some(data) => {
-                if let rkv::Value::Json(data) = data {
-                    Ok(Some(serde_json::from_str::<T>(data)?))
-                } else {
-                    Err(NimbusError::InvalidPersistedData)
-                }
-            }
+        match reader.read_json(self, key)? {
+            Some(data) => Ok(Some(serde_json::from_str::<T>(&data)?)),
             None => Ok(None),
         }
     }
 
-    pub fn collect_all<'r, T, R>(&self, reader: &'r R) -> Result<Vec<T>>
+    pub fn collect_all_with_keys<'r, T, R>(&self, reader: &'r R) -> Result<Vec<(String, T)>>
     where
         R: Readable<'r>,
         T: serde::Serialize + for<'de> serde::Deserialize<'de>,
     {
         let mut result = Vec::new();
-        let mut iter = self.store.iter_start(reader)?;
-        while let Some(Ok((_, data))) = iter.next() {
-            if let rkv::Value::Json(data) = data {
-                result.push(serde_json::from_str::<T>(&data)?);
-            }
+        for (key, data) in reader.read_all_json(self)? {
+            result.push((key, serde_json::from_str::<T>(&data)?));
         }
         Ok(result)
     }
+
+    pub fn collect_all<'r, T, R>(&self, reader: &'r R) -> Result<Vec<T>>
+    where
+        R: Readable<'r>,
+        T: serde::Serialize + for<'de> serde::Deserialize<'de>,
+    {
+        Ok(self
+            .collect_all_with_keys(reader)?
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect())
+    }
 }
 
 /// Database used to access persisted data
-/// This an abstraction around an Rkv database
+/// This an abstraction around an Rkv database, or an in-memory equivalent
 /// An instance on this database is created each time the component is loaded
 /// if there is persisted data, the `get` functions should retrieve it
 pub struct Database {
-    rkv: Rkv,
+    backend: Box<dyn Backend>,
     meta_store: SingleStore,
     experiment_store: SingleStore,
     enrollment_store: SingleStore,
     updates_store: SingleStore,
+    // Not set when the database is kept in memory, since there is then nothing
+    // to restore after a corruption.
+    meta_backup_path: Option<PathBuf>,
+    // The contents of the meta backup file as we last read or wrote it, so that
+    // we only rewrite it when something has changed.
+    meta_backup: Mutex<Option<String>>,
 }
 
 impl Database {
@@ -210,24 +706,108 @@ impl Database {
     /// # Arguments
     /// - `path`: A path to the persisted data, this is provided by the consuming application
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let rkv = Self::open_rkv(path)?;
-        let meta_store = rkv.open_single("meta", StoreOptions::create())?;
-        let experiment_store = rkv.open_single("experiments", StoreOptions::create())?;
-        let enrollment_store = rkv.open_single("enrollments", StoreOptions::create())?;
-        let updates_store = rkv.open_single("updates", StoreOptions::create())?;
+        let meta_backup_path = path.as_ref().join(META_BACKUP_FILENAME);
+        let (backend, was_recreated) = RkvBackend::open(path)?;
         let db = Self {
-            rkv,
-            meta_store: SingleStore::new(meta_store),
-            experiment_store: SingleStore::new(experiment_store),
-            enrollment_store: SingleStore::new(enrollment_store),
-            updates_store: SingleStore::new(updates_store),
+            meta_backup: Mutex::new(fs::read_to_string(&meta_backup_path).ok()),
+            meta_backup_path: Some(meta_backup_path),
+            ..Self::with_backend(Box::new(backend))
         };
-        db.maybe_upgrade()?;
+        db.initialize(was_recreated)?;
+        Ok(db)
+    }
+
+    /// Constructor for a database which keeps all of its data in memory, so it
+    /// always starts out empty and nothing outlives it.
+    pub fn new_in_memory() -> Result<Self> {
+        let db = Self::with_backend(Box::new(MemoryBackend::new()));
+        db.initialize(false)?;
         Ok(db)
     }
 
+    fn with_backend(backend: Box<dyn Backend>) -> Self {
+        Self {
+            backend,
+            meta_store: SingleStore::new(StoreId::Meta),
+            experiment_store: SingleStore::new(StoreId::Experiments),
+            enrollment_store: SingleStore::new(StoreId::Enrollments),
+            updates_store: SingleStore::new(StoreId::Updates),
+            meta_backup: Mutex::new(None),
+            meta_backup_path: None,
+        }
+    }
+
+    fn initialize(&self, was_recreated: bool) -> Result<()> {
+        self.maybe_upgrade()?;
+        if was_recreated {
+            self.restore_meta_backup()?;
+        }
+        self.update_meta_backup();
+        Ok(())
+    }
+
+    /// Copy the current values of the critical `Meta` keys to the backup file,
+    /// if they have changed since we last did so. This should be called after
+    /// committing any transaction which may have changed them.
+    ///
+    /// The backup is best-effort, so failures are logged rather than returned.
+    pub fn update_meta_backup(&self) {
+        if let Err(e) = self.try_update_meta_backup() {
+            log::warn!("Failed to update the metadata backup: {}", e);
+        }
+    }
+
+    fn try_update_meta_backup(&self) -> Result<()> {
+        let meta_backup_path = match &self.meta_backup_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let reader = self.read()?;
+        let mut values = BTreeMap::new();
+        for key in BACKED_UP_META_KEYS {
+            if let Some(value) = self.meta_store.get::<serde_json::Value, _>(&reader, key)? {
+                values.insert(*key, value);
+            }
+        }
+        let contents = serde_json::to_string(&values)?;
+        let mut meta_backup = self.meta_backup.lock().unwrap();
+        if meta_backup.as_ref() != Some(&contents) {
+            write_file_atomically(meta_backup_path, &contents)?;
+            meta_backup.replace(contents);
+        }
+        Ok(())
+    }
+
+    /// Called after the database had to be recreated because it was corrupt: restore
+    /// the critical `Meta` values from the backup file, and record the corruption.
+    fn restore_meta_backup(&self) -> Result<()> {
+        let mut writer = self.write()?;
+        let backup = self.meta_backup.lock().unwrap().clone();
+        if let Some(contents) = backup {
+            match serde_json::from_str::<BTreeMap<String, serde_json::Value>>(&contents) {
+                Ok(values) => {
+                    for key in BACKED_UP_META_KEYS {
+                        if let Some(value) = values.get(*key) {
+                            self.meta_store.put(&mut writer, key, value)?;
+                        }
+                    }
+                }
+                Err(e) => log::warn!("Ignoring invalid metadata backup: {}", e),
+            }
+        }
+        let mut corruptions = self
+            .meta_store
+            .get::<Vec<u64>, _>(&writer, DB_KEY_DB_CORRUPTIONS)?
+            .unwrap_or_default();
+        corruptions.push(now_secs());
+        self.meta_store
+            .put(&mut writer, DB_KEY_DB_CORRUPTIONS, &corruptions)?;
+        writer.commit()?;
+        Ok(())
+    }
+
     fn maybe_upgrade(&self) -> Result<()> {
-        let mut writer = self.rkv.write()?;
+        let mut writer = self.write()?;
         let db_version = self.meta_store.get::<u16, _>(&writer, DB_KEY_DB_VERSION)?;
         match db_version {
             Some(DB_VERSION) => {
@@ -242,9 +822,31 @@ impl Database {
                 self.experiment_store.clear(&mut writer)?;
                 self.enrollment_store.clear(&mut writer)?;
             }
-            _ => {
-                log::error!("Unknown database version. Wiping everything.");
-                self.meta_store.clear(&mut writer)?;
+            Some(version) if (1..DB_VERSION).contains(&version) => {
+                if let Err(e) = self.apply_migrations(&mut writer, version) {
+                    // Throw away whatever the failed migration did, and start anew
+                    // with what we know we can still read.
+                    log::error!(
+                        "Failed to migrate database from v{}: {}. Discarding experiments and enrollments.",
+                        version,
+                        e
+                    );
+                    // Dropping the writer without committing aborts the transaction.
+                    drop(writer);
+                    writer = self.write()?;
+                    self.experiment_store.clear(&mut writer)?;
+                    self.enrollment_store.clear(&mut writer)?;
+                }
+            }
+            Some(version) => {
+                // Most likely a newer version of the library has written to this database
+                // and the app was downgraded. We can't know what changed in experiments and
+                // enrollments, but we keep the metadata (in particular the nimbus_id and the
+                // user's participation choice), whose keys are never repurposed.
+                log::warn!(
+                    "Unknown database version {}. Discarding experiments and enrollments.",
+                    version
+                );
                 self.experiment_store.clear(&mut writer)?;
                 self.enrollment_store.clear(&mut writer)?;
             }
@@ -260,6 +862,35 @@ impl Database {
         Ok(())
     }
 
+    /// Apply each migration step from `from_version` up to `DB_VERSION`, in order.
+    fn apply_migrations(&self, writer: &mut Writer, from_version: u16) -> Result<()> {
+        for version in from_version..DB_VERSION {
+            log::info!("Migrating database from v{} to v{}", version, version + 1);
+            MIGRATIONS[(version - 1) as usize](self, writer)?;
+        }
+        Ok(())
+    }
+
+    /// Version 2 replaced the free-form `reason` string of `EnrollmentStatus::Error`
+    /// with an `EnrollmentErrorReason`, so rewrite any enrollments in that state.
+    fn migrate_v1_to_v2(&self, writer: &mut Writer) -> Result<()> {
+        let enrollments = self
+            .enrollment_store
+            .collect_all_with_keys::<serde_json::Value, _>(writer)?;
+        for (slug, mut enrollment) in enrollments {
+            let legacy_reason = match enrollment
+                .pointer("/status/Error/reason")
+                .and_then(|reason| reason.as_str())
+            {
+                Some(reason) => EnrollmentErrorReason::from_legacy_reason(reason),
+                None => continue,
+            };
+            enrollment["status"]["Error"]["reason"] = serde_json::to_value(legacy_reason)?;
+            self.enrollment_store.put(writer, &slug, &enrollment)?;
+        }
+        Ok(())
+    }
+
     /// Gets a Store object, which used with the writer returned by
     /// `self.write()` to update the database in a transaction.
     pub fn get_store(&self, store_id: StoreId) -> &SingleStore {
@@ -271,49 +902,18 @@ impl Database {
         }
     }
 
-    fn open_rkv<P: AsRef<Path>>(path: P) -> Result<Rkv> {
-        let path = std::path::Path::new(path.as_ref()).join("db");
-        log::debug!("Database path: {:?}", path.display());
-        fs::create_dir_all(&path)?;
-        let rkv = match rkv_new(&path) {
-            Ok(rkv) => Ok(rkv),
-            Err(rkv_error) => {
-                match rkv_error {
-                    // For some errors we just delete the DB and start again.
-                    StoreError::DatabaseCorrupted | StoreError::FileInvalid => {
-                        // On one hand this seems a little dangerous, but on
-                        // the other hand avoids us knowing about the
-                        // underlying implementation (ie, how do we know what
-                        // files might exist in all cases?)
-                        log::warn!(
-                            "Database at '{}' appears corrupt - removing and recreating",
-                            path.display()
-                        );
-                        fs::remove_dir_all(&path)?;
-                        fs::create_dir_all(&path)?;
-                        // TODO: Once we have glean integration we want to
-                        // record telemetry here.
-                        rkv_new(&path)
-                    }
-                    // All other errors are fatal.
-                    _ => Err(rkv_error),
-                }
-            }
-        }?;
-        log::debug!("Database initialized");
-        Ok(rkv)
-    }
-
     /// Function used to obtain a "reader" which is used for read-only transactions.
     pub fn read(&self) -> Result<Reader> {
-        Ok(self.rkv.read()?)
+        Ok(Reader {
+            transaction: self.backend.read()?,
+        })
     }
 
     /// Function used to obtain a "writer" which is used for transactions.
     /// The `writer.commit();` must be called to commit data added via the
     /// writer.
     pub fn write(&self) -> Result<Writer> {
-        Ok(self.rkv.write()?)
+        Ok(Writer::new(self.backend.write()?))
     }
 
     /// Function used to retrieve persisted data outside of a transaction.
@@ -329,38 +929,19 @@ impl Database {
         store_id: StoreId,
         key: &str,
     ) -> Result<Option<T>> {
-        let reader = self.rkv.read()?;
-        let persisted_data = self.get_store(store_id).store.get(&reader, key)?;
-        match persisted_data {
-            Some(data) => {
-                if let rkv::Value::Json(data) = data {
-                    Ok(Some(serde_json::from_str::<T>(data)?))
-                } else {
-                    Err(NimbusError::InvalidPersistedData)
-                }
-            }
-            None => Ok(None),
-        }
+        let reader = self.read()?;
+        self.get_store(store_id).get(&reader, key)
     }
 
     // Function for collecting all items in a store outside of a transaction.
     // Only available for tests; product code should always be using transactions.
-    // Iters are a bit tricky - would be nice to make them generic, but this will
-    // do for our use-case.
     #[cfg(test)]
     pub fn collect_all<T: serde::Serialize + for<'de> serde::Deserialize<'de>>(
         &self,
         store_id: StoreId,
     ) -> Result<Vec<T>> {
-        let mut result = Vec::new();
-        let reader = self.rkv.read()?;
-        let mut iter = self.get_store(store_id).store.iter_start(&reader)?;
-        while let Some(Ok((_, data))) = iter.next() {
-            if let rkv::Value::Json(data) = data {
-                result.push(serde_json::from_str::<T>(&data)?);
-            }
-        }
-        Ok(result)
+        let reader = self.read()?;
+        self.get_store(store_id).collect_all(&reader)
     }
 }
 
@@ -375,13 +956,10 @@ mod tests {
         let path = "test_upgrade_1";
         let tmp_dir = TempDir::new(path)?;
 
-        let rkv = Database::open_rkv(&tmp_dir)?;
-        let _meta_store = rkv.open_single("meta", StoreOptions::create())?;
-        let experiment_store =
-            SingleStore::new(rkv.open_single("experiments", StoreOptions::create())?);
-        let enrollment_store =
-            SingleStore::new(rkv.open_single("enrollments", StoreOptions::create())?);
-        let mut writer = rkv.write()?;
+        let (backend, _) = RkvBackend::open(&tmp_dir)?;
+        let experiment_store = SingleStore::new(StoreId::Experiments);
+        let enrollment_store = SingleStore::new(StoreId::Enrollments);
+        let mut writer = Writer::new(backend.write()?);
         enrollment_store.put(&mut writer, "foo", &"bar".to_owned())?;
         experiment_store.put(&mut writer, "bobo", &"tron".to_owned())?;
         writer.commit()?;
@@ -399,14 +977,13 @@ mod tests {
         let path = "test_upgrade_unknown";
         let tmp_dir = TempDir::new(path)?;
 
-        let rkv = Database::open_rkv(&tmp_dir)?;
-        let meta_store = SingleStore::new(rkv.open_single("meta", StoreOptions::create())?);
-        let experiment_store =
-            SingleStore::new(rkv.open_single("experiments", StoreOptions::create())?);
-        let enrollment_store =
-            SingleStore::new(rkv.open_single("enrollments", StoreOptions::create())?);
-        let mut writer = rkv.write()?;
+        let (backend, _) = RkvBackend::open(&tmp_dir)?;
+        let meta_store = SingleStore::new(StoreId::Meta);
+        let experiment_store = SingleStore::new(StoreId::Experiments);
+        let enrollment_store = SingleStore::new(StoreId::Enrollments);
+        let mut writer = Writer::new(backend.write()?);
         meta_store.put(&mut writer, DB_KEY_DB_VERSION, &u16::MAX)?;
+        meta_store.put(&mut writer, "nimbus-id", &"some-id".to_owned())?;
         enrollment_store.put(&mut writer, "foo", &"bar".to_owned())?;
         experiment_store.put(&mut writer, "bobo", &"tron".to_owned())?;
         writer.commit()?;
@@ -414,10 +991,183 @@ mod tests {
         assert_eq!(db.get(StoreId::Meta, DB_KEY_DB_VERSION)?, Some(DB_VERSION));
         assert!(db.collect_all::<String>(StoreId::Enrollments)?.is_empty());
         assert!(db.collect_all::<String>(StoreId::Experiments)?.is_empty());
+        // The metadata survives a downgrade.
+        assert_eq!(
+            db.get::<String>(StoreId::Meta, "nimbus-id")?,
+            Some("some-id".to_owned())
+        );
 
         Ok(())
     }
 
+    #[test]
+    fn test_migrations_cover_all_versions() {
+        assert_eq!(MIGRATIONS.len(), (DB_VERSION - 1) as usize);
+    }
+
+    #[test]
+    fn test_migrate_v1_to_v2() -> Result<()> {
+        use crate::enrollment::{EnrollmentStatus, ExperimentEnrollment};
+        use serde_json::json;
+
+        let tmp_dir = TempDir::new("test_migrate_v1_to_v2")?;
+        let db = Database::new(&tmp_dir)?;
+        let mut writer = db.write()?;
+        let enrollment_store = db.get_store(StoreId::Enrollments);
+        enrollment_store.put(
+            &mut writer,
+            "secure-gold",
+            &json!({
+                "slug": "secure-gold",
+                "status": {"Error": {"reason": "EvaluationError: Unexpected token"}}
+            }),
+        )?;
+        enrollment_store.put(
+            &mut writer,
+            "secure-silver",
+            &json!({
+                "slug": "secure-silver",
+                "status": {"NotEnrolled": {"reason": "NotSelected"}}
+            }),
+        )?;
+
+        db.migrate_v1_to_v2(&mut writer)?;
+
+        let enrollment: ExperimentEnrollment = enrollment_store
+            .get(&writer, "secure-gold")?
+            .expect("should exist");
+        assert_eq!(
+            enrollment.status,
+            EnrollmentStatus::Error {
+                reason: EnrollmentErrorReason::EvaluationError("Unexpected token".to_owned())
+            }
+        );
+        let enrollment: ExperimentEnrollment = enrollment_store
+            .get(&writer, "secure-silver")?
+            .expect("should exist");
+        assert!(matches!(
+            enrollment.status,
+            EnrollmentStatus::NotEnrolled { .. }
+        ));
+        writer.commit()?;
+        Ok(())
+    }
+
+    // Write a database fixture from `tests/fixtures/` as it would have been
+    // persisted by an older version of the library.
+    fn write_fixture(path: &Path, fixture: &str) -> Result<()> {
+        let fixture: serde_json::Value = serde_json::from_str(fixture)?;
+        let (backend, _) = RkvBackend::open(path)?;
+        let mut writer = Writer::new(backend.write()?);
+        for (name, store_id) in &[
+            ("meta", StoreId::Meta),
+            ("experiments", StoreId::Experiments),
+            ("enrollments", StoreId::Enrollments),
+            ("updates", StoreId::Updates),
+        ] {
+            let store = SingleStore::new(*store_id);
+            if let Some(items) = fixture[*name].as_object() {
+                for (key, value) in items {
+                    store.put(&mut writer, key, value)?;
+                }
+            }
+        }
+        writer.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_db_upgrade_from_v1_fixture() -> Result<()> {
+        use crate::enrollment::{
+            get_enrollments, get_global_user_participation, EnrollmentStatus, ExperimentEnrollment,
+        };
+        use crate::Experiment;
+
+        let tmp_dir = TempDir::new("test_db_upgrade_from_v1_fixture")?;
+        write_fixture(tmp_dir.path(), include_str!("../tests/fixtures/db-v1.json"))?;
+
+        let db = Database::new(&tmp_dir)?;
+        let reader = db.read()?;
+        let meta_store = db.get_store(StoreId::Meta);
+        let enrollment_store = db.get_store(StoreId::Enrollments);
+        assert_eq!(
+            meta_store.get(&reader, DB_KEY_DB_VERSION)?,
+            Some(DB_VERSION)
+        );
+        assert_eq!(
+            meta_store.get::<String, _>(&reader, "nimbus-id")?,
+            Some("29686b11-00c0-4905-b5e4-f5f945eda60a".to_owned())
+        );
+        assert!(!get_global_user_participation(&db, &reader)?);
+        let experiments: Vec<Experiment> =
+            db.get_store(StoreId::Experiments).collect_all(&reader)?;
+        assert_eq!(experiments.len(), 2);
+        // Every enrollment can be read after the migration.
+        let enrollments: Vec<ExperimentEnrollment> = enrollment_store.collect_all(&reader)?;
+        assert_eq!(enrollments.len(), 4);
+        let enrolled = get_enrollments(&db, &reader)?;
+        assert_eq!(enrolled.len(), 1);
+        assert_eq!(enrolled[0].slug, "secure-gold");
+        assert_eq!(enrolled[0].branch_slug, "treatment");
+        let silver: ExperimentEnrollment = enrollment_store
+            .get(&reader, "secure-silver")?
+            .expect("should exist");
+        assert_eq!(
+            silver.status,
+            EnrollmentStatus::Error {
+                reason: EnrollmentErrorReason::EvaluationError("Unexpected token".to_owned())
+            }
+        );
+        let bronze: ExperimentEnrollment = enrollment_store
+            .get(&reader, "secure-bronze")?
+            .expect("should exist");
+        assert_eq!(
+            bronze.status,
+            EnrollmentStatus::Error {
+                reason: EnrollmentErrorReason::MissingRandomizationUnit
+            }
+        );
+        Ok(())
+    }
+
+    // Every kind of free-form error reason version 1 persisted maps to its
+    // structured equivalent.
+    #[test]
+    fn test_db_upgrade_from_v1_error_reasons_fixture() -> Result<()> {
+        use crate::enrollment::{EnrollmentStatus, ExperimentEnrollment};
+
+        let tmp_dir = TempDir::new("test_db_upgrade_from_v1_error_reasons_fixture")?;
+        write_fixture(
+            tmp_dir.path(),
+            include_str!("../tests/fixtures/enrollment-errors-v1.json"),
+        )?;
+
+        let db = Database::new(&tmp_dir)?;
+        let reader = db.read()?;
+        let enrollment_store = db.get_store(StoreId::Enrollments);
+        let expected = vec![
+            (
+                "missing-randomization-unit",
+                EnrollmentErrorReason::MissingRandomizationUnit,
+            ),
+            ("invalid-targeting", EnrollmentErrorReason::InvalidTargeting),
+            (
+                "evaluation-error",
+                EnrollmentErrorReason::EvaluationError("Unexpected token".to_owned()),
+            ),
+            (
+                "unknown",
+                EnrollmentErrorReason::Unknown("Something else".to_owned()),
+            ),
+        ];
+        for (slug, reason) in expected {
+            let enrollment: ExperimentEnrollment =
+                enrollment_store.get(&reader, slug)?.expect("should exist");
+            assert_eq!(enrollment.status, EnrollmentStatus::Error { reason });
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_corrupt_db() -> Result<()> {
         let path = "test_corrupt_db";
@@ -425,12 +1175,7 @@ mod tests {
 
         let db_dir = tmp_dir.path().join("db");
         fs::create_dir(db_dir.clone())?;
-
-        // The database filename differs depending on the rkv mode.
-        #[cfg(feature = "rkv-safe-mode")]
-        let db_file = db_dir.join("data.safe.bin");
-        #[cfg(not(feature = "rkv-safe-mode"))]
-        let db_file = db_dir.join("data.mdb");
+        let db_file = db_dir.join(DB_FILENAME);
 
         let garbage = b"Not a database!";
         let garbage_len = garbage.len() as u64;
@@ -442,7 +1187,135 @@ mod tests {
         assert_ne!(fs::metadata(&db_file)?.len(), garbage_len);
         Ok(())
     }
-}
 
-// TODO: Add unit tests
-// Possibly by using a trait for persistence and mocking it to test the persistence.
+    // Make the database under `path` unreadable, so that it gets recreated the next time it's opened.
+    fn corrupt_db(path: &Path) -> Result<()> {
+        fs::write(path.join("db").join(DB_FILENAME), b"Not a database!")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_db_restores_meta() -> Result<()> {
+        let tmp_dir = TempDir::new("test_corrupt_db_restores_meta")?;
+        let db = Database::new(&tmp_dir)?;
+        let mut writer = db.write()?;
+        let meta_store = db.get_store(StoreId::Meta);
+        meta_store.put(&mut writer, DB_KEY_NIMBUS_ID, &"some-id".to_owned())?;
+        meta_store.put(&mut writer, DB_KEY_GLOBAL_USER_PARTICIPATION, &false)?;
+        db.get_store(StoreId::Enrollments)
+            .put(&mut writer, "foo", &"bar".to_owned())?;
+        writer.commit()?;
+        db.update_meta_backup();
+        drop(db);
+
+        corrupt_db(tmp_dir.path())?;
+        let db = Database::new(&tmp_dir)?;
+        assert_eq!(
+            db.get::<String>(StoreId::Meta, DB_KEY_NIMBUS_ID)?,
+            Some("some-id".to_owned())
+        );
+        assert_eq!(
+            db.get::<bool>(StoreId::Meta, DB_KEY_GLOBAL_USER_PARTICIPATION)?,
+            Some(false)
+        );
+        // Everything else is gone.
+        assert!(db.collect_all::<String>(StoreId::Enrollments)?.is_empty());
+        // And we recorded the corruption.
+        let corruptions = db
+            .get::<Vec<u64>>(StoreId::Meta, DB_KEY_DB_CORRUPTIONS)?
+            .unwrap();
+        assert_eq!(corruptions.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_file_atomically() -> Result<()> {
+        let tmp_dir = TempDir::new("test_write_file_atomically")?;
+        let path = tmp_dir.path().join("file.json");
+        write_file_atomically(&path, "first")?;
+        write_file_atomically(&path, "second")?;
+        assert_eq!(fs::read_to_string(&path)?, "second");
+        // No temporary files are left behind.
+        assert_eq!(fs::read_dir(tmp_dir.path())?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_meta_backup_follows_deletions() -> Result<()> {
+        let tmp_dir = TempDir::new("test_meta_backup_follows_deletions")?;
+        let db = Database::new(&tmp_dir)?;
+        let meta_store = db.get_store(StoreId::Meta);
+        let mut writer = db.write()?;
+        meta_store.put(&mut writer, DB_KEY_NIMBUS_ID, &"some-id".to_owned())?;
+        writer.commit()?;
+        db.update_meta_backup();
+        // Eg, the telemetry identifiers were reset.
+        let mut writer = db.write()?;
+        meta_store.delete(&mut writer, DB_KEY_NIMBUS_ID)?;
+        writer.commit()?;
+        db.update_meta_backup();
+        drop(db);
+
+        corrupt_db(tmp_dir.path())?;
+        let db = Database::new(&tmp_dir)?;
+        assert_eq!(db.get::<String>(StoreId::Meta, DB_KEY_NIMBUS_ID)?, None);
+        Ok(())
+    }
+
+    #[cfg(feature = "rkv-safe-mode")]
+    #[test]
+    fn test_database_in_use() -> Result<()> {
+        let tmp_dir = TempDir::new("test_database_in_use")?;
+        let db = Database::new(&tmp_dir)?;
+        // Another instance, eg, in another process, can't open the database while
+        // this one has it open...
+        assert!(matches!(
+            Database::new(&tmp_dir),
+            Err(NimbusError::DatabaseInUse)
+        ));
+        // ...but it can once this one is done with it.
+        drop(db);
+        Database::new(&tmp_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_memory_db() -> Result<()> {
+        let db = Database::new_in_memory()?;
+        assert_eq!(db.get(StoreId::Meta, DB_KEY_DB_VERSION)?, Some(DB_VERSION));
+        let store = db.get_store(StoreId::Experiments);
+
+        let mut writer = db.write()?;
+        store.put(&mut writer, "b", &"two".to_owned())?;
+        store.put(&mut writer, "a", &"one".to_owned())?;
+        // The transaction sees its own writes, but nobody else does until it's committed.
+        assert_eq!(
+            store.get::<String, _>(&writer, "a")?,
+            Some("one".to_owned())
+        );
+        let reader = db.read()?;
+        assert_eq!(store.get::<String, _>(&reader, "a")?, None);
+        writer.commit()?;
+        // A reader keeps seeing the data as it was when it was created.
+        assert_eq!(store.get::<String, _>(&reader, "a")?, None);
+        drop(reader);
+        assert_eq!(
+            db.collect_all::<String>(StoreId::Experiments)?,
+            vec!["one".to_owned(), "two".to_owned()]
+        );
+        // Stores are kept separate.
+        assert!(db.collect_all::<String>(StoreId::Enrollments)?.is_empty());
+
+        // Dropping a writer without committing it aborts the transaction.
+        let mut writer = db.write()?;
+        store.delete(&mut writer, "a")?;
+        store.clear(&mut writer)?;
+        drop(writer);
+        assert_eq!(db.collect_all::<String>(StoreId::Experiments)?.len(), 2);
+
+        // Like rkv, deleting a key which doesn't exist is an error.
+        let mut writer = db.write()?;
+        assert!(store.delete(&mut writer, "c").is_err());
+        Ok(())
+    }
+}
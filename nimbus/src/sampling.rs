@@ -98,7 +98,7 @@ pub(crate) fn ratio_sample<T: serde::Serialize>(input: T, ratios: &[u32]) -> Res
 /// # Errors:
 /// Would return an error if the hashing function fails to generate a hash
 /// that is larger than 6 bytes (Should never occur)
-fn truncated_hash<T: serde::Serialize>(data: T) -> Result<[u8; 6]> {
+pub(crate) fn truncated_hash<T: serde::Serialize>(data: T) -> Result<[u8; 6]> {
     let mut hasher = Sha256::new();
     let data_str = serde_json::to_string(&data)?;
     hasher.update(data_str.as_bytes());
@@ -6,10 +6,15 @@
 //! safe updating from the server.
 
 use crate::error::Result;
-use crate::persistence::{Database, StoreId, Writer};
-use crate::Experiment;
+use crate::persistence::{Database, Readable, StoreId, Writer};
+use crate::{CollectionState, Experiment};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-const KEY_PENDING_UPDATES: &str = "pending-experiment-updates";
+pub(crate) const KEY_PENDING_UPDATES: &str = "pending-experiment-updates";
+const KEY_COLLECTION_STATE: &str = "remote-settings-collection";
+// Unlike the other keys here, this one lives in the Meta store, since a backoff
+// requested by the server must be honored even across upgrades.
+pub(crate) const DB_KEY_BACKOFF_DEADLINE: &str = "backoff-deadline";
 
 pub fn write_pending_experiments(
     db: &Database,
@@ -20,6 +25,16 @@ pub fn write_pending_experiments(
         .put(writer, KEY_PENDING_UPDATES, &experiments)
 }
 
+/// Read the pending experiments without removing them, eg, to preview
+/// the effect of applying them.
+pub fn read_pending_experiments<'r>(
+    db: &Database,
+    reader: &'r impl Readable<'r>,
+) -> Result<Option<Vec<Experiment>>> {
+    db.get_store(StoreId::Updates)
+        .get::<Vec<Experiment>, _>(reader, KEY_PENDING_UPDATES)
+}
+
 pub fn read_and_remove_pending_experiments(
     db: &Database,
     writer: &mut Writer,
@@ -27,11 +42,11 @@ pub fn read_and_remove_pending_experiments(
     let store = db.get_store(StoreId::Updates);
     let experiments = store.get::<Vec<Experiment>, _>(writer, KEY_PENDING_UPDATES)?;
 
-    // Only clear the store if there's updates available.
+    // Only remove the updates if there are some available.
     // If we're accidentally called from the main thread,
     // we don't want to be writing unless we absolutely have to.
     if experiments.is_some() {
-        store.clear(writer)?;
+        store.delete(writer, KEY_PENDING_UPDATES)?;
     }
 
     // An empty Some(vec![]) is "updates of an empty list" i.e. unenrolling from all experiments
@@ -39,6 +54,65 @@ pub fn read_and_remove_pending_experiments(
     Ok(experiments)
 }
 
+/// Read the state of the experiments collection as we last synced it with the
+/// server, if we did.
+pub fn read_collection_state<'r>(
+    db: &Database,
+    reader: &'r impl Readable<'r>,
+) -> Result<CollectionState> {
+    Ok(db
+        .get_store(StoreId::Updates)
+        .get(reader, KEY_COLLECTION_STATE)?
+        .unwrap_or_default())
+}
+
+pub fn write_collection_state(
+    db: &Database,
+    writer: &mut Writer,
+    state: &CollectionState,
+) -> Result<()> {
+    db.get_store(StoreId::Updates)
+        .put(writer, KEY_COLLECTION_STATE, state)
+}
+
+/// Read the time until which the server asked us not to make requests, if it
+/// did. It's stored in seconds since the Unix epoch, since unlike an `Instant`,
+/// it must still make sense after a restart.
+pub fn read_backoff_deadline<'r>(
+    db: &Database,
+    reader: &'r impl Readable<'r>,
+) -> Result<Option<SystemTime>> {
+    Ok(db
+        .get_store(StoreId::Meta)
+        .get::<u64, _>(reader, DB_KEY_BACKOFF_DEADLINE)?
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)))
+}
+
+pub fn write_backoff_deadline(
+    db: &Database,
+    writer: &mut Writer,
+    deadline: Option<SystemTime>,
+) -> Result<()> {
+    let store = db.get_store(StoreId::Meta);
+    match deadline {
+        Some(deadline) => {
+            let since_epoch = deadline.duration_since(UNIX_EPOCH).unwrap_or_default();
+            // Round up, so that we never stop backing off too early.
+            let secs = since_epoch.as_secs() + u64::from(since_epoch.subsec_nanos() > 0);
+            store.put(writer, DB_KEY_BACKOFF_DEADLINE, &secs)
+        }
+        None => {
+            if store
+                .get::<u64, _>(writer, DB_KEY_BACKOFF_DEADLINE)?
+                .is_some()
+            {
+                store.delete(writer, DB_KEY_BACKOFF_DEADLINE)?;
+            }
+            Ok(())
+        }
+    }
+}
+
 // This test crashes lmdb for reasons that make no sense, so only run it
 // in the "safe mode" backend.
 #[cfg(feature = "rkv-safe-mode")]
@@ -61,6 +135,10 @@ fn test_reading_writing_and_removing_experiments() -> Result<()> {
 
     write_pending_experiments(&db, &mut writer, fetched)?;
 
+    // Peeking at the stashed updates doesn't remove them.
+    let pending = read_pending_experiments(&db, &writer)?;
+    assert_eq!(pending.unwrap().len(), 1);
+
     // Now, we come to get the stashed updates, and they should be
     // the same.
     let pending = read_and_remove_pending_experiments(&db, &mut writer)?;
@@ -76,3 +154,50 @@ fn test_reading_writing_and_removing_experiments() -> Result<()> {
     writer.commit()?;
     Ok(())
 }
+
+#[test]
+fn test_collection_state_outlives_pending_experiments() -> Result<()> {
+    let db = Database::new_in_memory()?;
+    let mut writer = db.write()?;
+    assert_eq!(
+        read_collection_state(&db, &writer)?,
+        CollectionState::default()
+    );
+    let state = CollectionState {
+        last_modified: Some(1_602_197_324_372),
+        records: vec![(
+            "secure-gold".to_owned(),
+            serde_json::json!({"id": "secure-gold"}),
+        )]
+        .into_iter()
+        .collect(),
+    };
+    write_collection_state(&db, &mut writer, &state)?;
+    write_pending_experiments(&db, &mut writer, vec![Default::default()])?;
+    assert!(read_and_remove_pending_experiments(&db, &mut writer)?.is_some());
+    assert_eq!(read_collection_state(&db, &writer)?, state);
+
+    writer.commit()?;
+    Ok(())
+}
+
+#[test]
+fn test_backoff_deadline() -> Result<()> {
+    let db = Database::new_in_memory()?;
+    let mut writer = db.write()?;
+    assert_eq!(read_backoff_deadline(&db, &writer)?, None);
+
+    let deadline = UNIX_EPOCH + Duration::from_millis(1_600_000_000_500);
+    write_backoff_deadline(&db, &mut writer, Some(deadline))?;
+    assert_eq!(
+        read_backoff_deadline(&db, &writer)?,
+        Some(UNIX_EPOCH + Duration::from_secs(1_600_000_001))
+    );
+
+    write_backoff_deadline(&db, &mut writer, None)?;
+    assert_eq!(read_backoff_deadline(&db, &writer)?, None);
+    // Clearing it again is fine too.
+    write_backoff_deadline(&db, &mut writer, None)?;
+    writer.commit()?;
+    Ok(())
+}
@@ -7,28 +7,51 @@ use nimbus::{error::Result, AppContext, NimbusClient, RemoteSettingsConfig};
 
 #[allow(dead_code)] // not clear why this is necessary...
 pub fn new_test_client(identifier: &str) -> Result<NimbusClient> {
-    use std::path::PathBuf;
     use tempdir::TempDir;
-    use url::Url;
+    let tmp_dir = TempDir::new(identifier)?;
+    new_test_client_in(tmp_dir.path())
+}
+
+// Like `new_test_client()`, but with the database under `db_path`, so that
+// several clients can share it.
+#[allow(dead_code)] // not clear why this is necessary...
+pub fn new_test_client_in(db_path: &std::path::Path) -> Result<NimbusClient> {
+    let _ = env_logger::try_init();
+    let aru = Default::default();
+    NimbusClient::new(test_app_context(), db_path, Some(test_config()), aru, None)
+}
+
+// Like `new_test_client()`, but with the database kept in memory.
+#[allow(dead_code)] // not clear why this is necessary...
+pub fn new_test_client_in_memory() -> Result<NimbusClient> {
     let _ = env_logger::try_init();
+    let aru = Default::default();
+    NimbusClient::new_in_memory(test_app_context(), Some(test_config()), aru, None)
+}
+
+fn test_config() -> RemoteSettingsConfig {
+    use std::path::PathBuf;
+    use url::Url;
     let mut dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     dir.push("tests/experiments");
-    let tmp_dir = TempDir::new(identifier)?;
     let url = Url::from_file_path(dir).expect("experiments dir should exist");
 
-    let config = RemoteSettingsConfig {
+    RemoteSettingsConfig {
         server_url: url.as_str().to_string(),
         bucket_name: "doesn't matter".to_string(),
         collection_name: "doesn't matter".to_string(),
-    };
-    let aru = Default::default();
-    let ctx = AppContext {
+        override_collections: vec![],
+        root_hash: None,
+    }
+}
+
+fn test_app_context() -> AppContext {
+    AppContext {
         app_name: "fenix".to_string(),
         app_id: "org.mozilla.fenix".to_string(),
         channel: "nightly".to_string(),
         ..Default::default()
-    };
-    NimbusClient::new(ctx, tmp_dir.path(), Some(config), aru)
+    }
 }
 
 #[allow(dead_code)] // not clear why this is necessary...
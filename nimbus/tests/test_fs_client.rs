@@ -28,12 +28,14 @@ fn test_simple() -> Result<()> {
         server_url: url.as_str().to_string(),
         bucket_name: "doesn't matter".to_string(),
         collection_name: "doesn't matter".to_string(),
+        override_collections: vec![],
+        root_hash: None,
     };
 
     let tmp_dir = TempDir::new("test_fs_client-test_simple")?;
 
     let aru = Default::default();
-    let client = NimbusClient::new(Default::default(), tmp_dir.path(), Some(config), aru)?;
+    let client = NimbusClient::new(Default::default(), tmp_dir.path(), Some(config), aru, None)?;
     client.fetch_experiments()?;
     client.apply_pending_experiments()?;
 
@@ -0,0 +1,46 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Testing clients created with the in-memory constructor, which is also the
+// `NimbusClient.newInMemory()` constructor of the bindings.
+
+mod common;
+
+#[cfg(test)]
+mod test {
+    use super::common::{exactly_two_experiments, new_test_client_in_memory};
+    use nimbus::error::Result;
+
+    #[test]
+    fn test_in_memory_client() -> Result<()> {
+        let client = new_test_client_in_memory()?;
+        client.initialize()?;
+        client.fetch_experiments()?;
+        assert_eq!(client.apply_pending_experiments()?.len(), 1);
+        assert_eq!(client.get_all_experiments()?.len(), 1);
+        assert!(client
+            .get_experiment_branch("secure-gold".to_owned())?
+            .is_some());
+
+        client.set_experiments_locally(exactly_two_experiments())?;
+        client.apply_pending_experiments()?;
+        assert_eq!(client.get_active_experiments()?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_memory_clients_are_separate() -> Result<()> {
+        let client = new_test_client_in_memory()?;
+        client.set_experiments_locally(exactly_two_experiments())?;
+        client.apply_pending_experiments()?;
+        assert_eq!(client.get_all_experiments()?.len(), 2);
+
+        // Nothing is shared with, or outlives, the first client.
+        let other_client = new_test_client_in_memory()?;
+        other_client.initialize()?;
+        assert!(other_client.get_all_experiments()?.is_empty());
+        assert!(other_client.get_active_experiments()?.is_empty());
+        Ok(())
+    }
+}
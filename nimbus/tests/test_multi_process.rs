@@ -0,0 +1,130 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Testing several processes sharing the same database, which only the LMDB
+// backend supports. Each test runs its "other" process by running itself again,
+// with the environment variables below telling it what to do.
+
+mod common;
+
+#[cfg(test)]
+mod test {
+    use super::common::{exactly_two_experiments, new_test_client_in};
+    use nimbus::error::{NimbusError, Result};
+    use std::env;
+    use std::path::Path;
+    use std::process::Command;
+    use tempdir::TempDir;
+
+    // When this is set, the test is running in the child process, and this is
+    // the path of the database it shares with the parent.
+    const CHILD_DB_PATH_VAR: &str = "NIMBUS_TEST_CHILD_DB_PATH";
+    // What the child process should do with the database.
+    const CHILD_ACTION_VAR: &str = "NIMBUS_TEST_CHILD_ACTION";
+
+    // What the "other" process does. We can't just open a second client in this
+    // process, since LMDB doesn't support opening the same environment twice.
+    fn run_child(db_path: &Path, action: &str) -> Result<()> {
+        let client = new_test_client_in(db_path)?;
+        match action {
+            "initialize" => {
+                assert!(matches!(
+                    client.initialize(),
+                    Err(NimbusError::DatabaseInUse)
+                ));
+            }
+            "enroll" => {
+                client.set_experiments_locally(exactly_two_experiments())?;
+                assert_eq!(client.apply_pending_experiments()?.len(), 2);
+            }
+            "opt-out" => {
+                assert_eq!(client.opt_out("secure-gold".to_owned())?.len(), 1);
+            }
+            _ => panic!("Unknown action {}", action),
+        }
+        Ok(())
+    }
+
+    // Run the test named `test_name` (in full, so that the child process only
+    // runs it) again in a child process, to do `action` with the database.
+    fn run_in_child_process(test_name: &str, db_path: &Path, action: &str) -> Result<()> {
+        let output = Command::new(env::current_exe()?)
+            .args(&["--exact", test_name, "--nocapture"])
+            .env(CHILD_DB_PATH_VAR, db_path)
+            .env(CHILD_ACTION_VAR, action)
+            .output()?;
+        assert!(
+            output.status.success(),
+            "The child process failed to {}:\n{}{}",
+            action,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        Ok(())
+    }
+
+    // If we're running in the child process, do what the parent asked for.
+    fn maybe_run_child() -> Option<Result<()>> {
+        let db_path = env::var_os(CHILD_DB_PATH_VAR)?;
+        let action = env::var(CHILD_ACTION_VAR).expect("the action should be set");
+        Some(run_child(Path::new(&db_path), &action))
+    }
+
+    // The "safe mode" backend doesn't support several processes sharing a
+    // database, so it doesn't let them.
+    #[cfg(feature = "rkv-safe-mode")]
+    #[test]
+    fn test_database_in_use_by_other_process() -> Result<()> {
+        if let Some(result) = maybe_run_child() {
+            return result;
+        }
+        const TEST_NAME: &str = "test::test_database_in_use_by_other_process";
+
+        let tmp_dir = TempDir::new("test_database_in_use_by_other_process")?;
+        let client = new_test_client_in(tmp_dir.path())?;
+        client.initialize()?;
+        run_in_child_process(TEST_NAME, tmp_dir.path(), "initialize")?;
+        // Once we're done with the database, another process can use it.
+        drop(client);
+        run_in_child_process(TEST_NAME, tmp_dir.path(), "enroll")?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "rkv-safe-mode"))]
+    #[test]
+    fn test_refresh_cache_after_change_in_other_process() -> Result<()> {
+        if let Some(result) = maybe_run_child() {
+            return result;
+        }
+        const TEST_NAME: &str = "test::test_refresh_cache_after_change_in_other_process";
+
+        let tmp_dir = TempDir::new("test_refresh_cache_after_change_in_other_process")?;
+        let client = new_test_client_in(tmp_dir.path())?;
+        client.initialize()?;
+        assert!(!client.refresh_cache()?);
+
+        run_in_child_process(TEST_NAME, tmp_dir.path(), "enroll")?;
+        // Our cache doesn't know about the new enrollments yet...
+        assert_eq!(
+            client.get_experiment_branch("secure-gold".to_owned())?,
+            None
+        );
+        // ...until we refresh it.
+        assert!(client.refresh_cache()?);
+        assert!(client
+            .get_experiment_branch("secure-gold".to_owned())?
+            .is_some());
+        assert!(!client.refresh_cache()?);
+
+        run_in_child_process(TEST_NAME, tmp_dir.path(), "opt-out")?;
+        assert_eq!(client.get_active_experiments()?.len(), 2);
+        assert!(client.refresh_cache()?);
+        assert_eq!(client.get_active_experiments()?.len(), 1);
+        assert_eq!(
+            client.get_experiment_branch("secure-gold".to_owned())?,
+            None
+        );
+        Ok(())
+    }
+}
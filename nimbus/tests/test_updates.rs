@@ -27,6 +27,7 @@ mod test {
     #[test]
     fn test_two_phase_update() -> Result<()> {
         let client = new_test_client("test_two_phase_update")?;
+        client.initialize()?;
         client.fetch_experiments()?;
 
         // We have fetched the experiments from the server, but not put them into use yet.
@@ -66,6 +67,7 @@ mod test {
     #[test]
     fn test_set_experiments_locally() -> Result<()> {
         let client = new_test_client("test_set_experiments_locally")?;
+        client.initialize()?;
         assert_experiment_count(&client, 0)?;
 
         client.set_experiments_locally(exactly_two_experiments())?;